@@ -4,94 +4,478 @@ mod api_tests;
 
 use crate::error::Error;
 use crate::types::*;
+use aws_sdk_ssm::model::{
+  Parameter, ParameterHistory, ParameterMetadata, ParameterStringFilter, ParameterType,
+};
+use aws_sdk_ssm::types::DateTime;
 use aws_sdk_ssm::Client;
+use aws_smithy_types::date_time::Format as DateTimeFormat;
 use base64;
+use hmac::{Hmac, Mac};
+#[cfg(unix)]
+use nix::sys::signal::{kill, Signal};
+#[cfg(unix)]
+use nix::unistd::{Group, Pid, User};
+use regex::bytes::{NoExpand, Regex as BytesRegex};
 use regex::Regex;
-use std::collections::HashMap;
+use sha2::Sha256;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
 use std::fs;
-use std::io::{BufRead, BufReader, Cursor};
-use std::path::PathBuf;
+#[cfg(unix)]
+use std::io::IsTerminal;
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
 use std::path::MAIN_SEPARATOR;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use tokio_stream::StreamExt;
 
 pub async fn process_parameters(
   options: ProcessParametersOptions,
-) -> Result<HashMap<String, String>, Error> {
-  let mut map = HashMap::<String, String>::new();
-  match options.mode {
-    Some(Mode::GET) => {
-      let params_map = read_from_aws(options.path.unwrap()).await?;
-      map.extend(params_map);
-    }
-    _ => (),
+) -> Result<HashMap<String, SecretValue>, Error> {
+  Ok(process_parameters_detailed(options).await?.vars)
+}
+
+// Unwraps every value, for formatting or giving to a command. The values are no longer zeroed
+pub fn expose_vars(vars: HashMap<String, SecretValue>) -> HashMap<String, String> {
+  vars
+    .into_iter()
+    .map(|(key, val)| (key, val.into_exposed()))
+    .collect()
+}
+
+// Same as process_parameters, also reporting where keys came from
+pub async fn process_parameters_detailed(
+  options: ProcessParametersOptions,
+) -> Result<ProcessedParameters, Error> {
+  let mut merger = Merger::new(&options.merge_config)?;
+  let mut secure_keys = HashSet::<String>::new();
+  if let Some(Mode::GET) = options.mode {
+    let path = options.path.unwrap();
+    let read = read_from_aws(path, options.decrypt).await?;
+    merger.merge(read.vars, |key| Source::GET {
+      name: read.names.get(key).cloned().unwrap_or_default(),
+      version: read.versions.get(key).copied().unwrap_or_default(),
+    })?;
+    secure_keys.extend(read.secure_keys);
+  }
+  if let Some(specs) = &options.params {
+    let read = read_params_from_aws(specs).await?;
+    merger.merge(read.vars, |key| Source::PARAM {
+      name: read.names.get(key).cloned().unwrap_or_default(),
+      version: read.versions.get(key).copied().unwrap_or_default(),
+    })?;
+    secure_keys.extend(read.secure_keys);
   }
-  if let Some(include_maps) = match &options.includes {
-    Some(path_bufs) => Some(read_pairs_from_files(path_bufs, true)?),
-    None => None,
-  } {
-    for include_map in include_maps.into_iter() {
-      map.extend(include_map);
+  if let Some(paths) = &options.includes {
+    for path in paths {
+      let pairs = read_numbered_pairs_from_file(path, true)?;
+      let lines: HashMap<String, usize> = pairs
+        .iter()
+        .map(|(line, Pair(key, _))| (key.to_owned(), *line))
+        .collect();
+      let include_map = pairs.into_iter().map(|(_, pair)| pair).collect();
+      merger.merge(include_map, |key| Source::INCLUDE {
+        path: path.to_owned(),
+        line: lines.get(key).copied().unwrap_or_default(),
+      })?;
     }
   };
-  if let Some(env_var_map) = match &options.env_vars {
-    Some(lines) => Some(merge_with_given(lines, false)?),
-    None => None,
-  } {
-    map.extend(env_var_map);
+  if let Some(lines) = &options.env_vars {
+    merger.merge(merge_with_given(lines, false)?, |_| Source::ENV_VAR)?;
   };
-  if let Some(env_var_map) = match &options.env_vars_base64 {
-    Some(lines) => Some(merge_with_given(lines, true)?),
-    None => None,
-  } {
-    map.extend(env_var_map);
+  if let Some(lines) = &options.env_vars_base64 {
+    merger.merge(merge_with_given(lines, true)?, |_| Source::ENV_VAR)?;
+  };
+  if let Some(paths) = &options.merges {
+    let merge_maps = merge_with_commands_config(paths, &merger.vars, &options.merge_config)?;
+    for (path, merge_map) in paths.iter().zip(merge_maps) {
+      merger.merge(merge_map, |_| Source::MERGE(path.clone()))?;
+    }
   };
-  if let Some(merge_maps) = match &options.merges {
-    Some(path_bufs) => Some(merge_with_commands(path_bufs, &map)?),
-    None => None,
-  } {
-    for merge_map in merge_maps.into_iter() {
-      map.extend(merge_map);
+  let Merger {
+    vars: map,
+    origins,
+    overrides,
+    ..
+  } = merger;
+  let transform = &options.key_transform;
+  let map = transform_keys(map, transform)?;
+  let (mut map, unmatched_patterns) = filter_keys(map, &options.key_filter)?;
+  let secure_keys = secure_keys
+    .iter()
+    .map(|key| transform_key(key, transform))
+    .filter(|key| map.contains_key(key))
+    .collect();
+  let mut origins: HashMap<String, Source> = origins
+    .into_iter()
+    .map(|(key, source)| (transform_key(&key, transform), source))
+    .filter(|(key, _)| map.contains_key(key))
+    .collect();
+  let overrides = overrides
+    .into_iter()
+    .map(|o| Override {
+      key: transform_key(&o.key, transform),
+      ..o
+    })
+    .filter(|o| map.contains_key(&o.key))
+    .collect();
+  let provided = [
+    ("PROVIDE_APPLICATION", options.app),
+    ("PROVIDE_TARGET", options.target),
+  ];
+  for (key, val) in provided {
+    if let (Some(val), false) = (val, map.contains_key(key)) {
+      map.insert(key.to_owned(), val);
+      origins.insert(key.to_owned(), Source::PROVIDE);
+    }
+  }
+  Ok(ProcessedParameters {
+    vars: map
+      .into_iter()
+      .map(|(key, val)| (key, SecretValue::from(val)))
+      .collect(),
+    secure_keys,
+    unmatched_patterns,
+    origins,
+    overrides,
+  })
+}
+
+// Merges sources in order, tracking which one set each key
+struct Merger {
+  vars: HashMap<String, String>,
+  origins: HashMap<String, Source>,
+  overrides: Vec<Override>,
+  strict: StrictMerge,
+  locked: Vec<(String, Regex)>,
+}
+
+const SOURCE_KINDS: [&str; 5] = ["get", "param", "include", "env-var", "merge"];
+
+impl Merger {
+  fn new(config: &MergeConfig) -> Result<Merger, Error> {
+    let locked = config
+      .locked
+      .iter()
+      .map(
+        |(kind, pattern)| match SOURCE_KINDS.contains(&kind.as_str()) {
+          true => Ok((kind.to_owned(), Regex::new(pattern)?)),
+          false => Err(Error::BadFormat(format!(
+            "Unknown source {kind}, expected one of {}",
+            SOURCE_KINDS.join(", ")
+          ))),
+        },
+      )
+      .collect::<Result<_, Error>>()?;
+    Ok(Merger {
+      vars: HashMap::new(),
+      origins: HashMap::new(),
+      overrides: vec![],
+      strict: config.strict,
+      locked,
+    })
+  }
+
+  fn merge(
+    &mut self,
+    vars: HashMap<String, String>,
+    source_of: impl Fn(&str) -> Source,
+  ) -> Result<(), Error> {
+    for (key, val) in sorted(vars) {
+      let source = source_of(&key);
+      if let Some(previous) = self.origins.get(&key) {
+        if self.vars.get(&key) != Some(&val) {
+          if self.is_locked(&key, previous) {
+            return Err(Error::MergeError(format!(
+              "{key} from {previous} is locked and cannot be overridden by {source}"
+            )));
+          }
+          if self.strict == StrictMerge::ERROR {
+            return Err(Error::MergeError(format!(
+              "{key} from {previous} is overridden by {source}"
+            )));
+          }
+          self.overrides.push(Override {
+            key: key.clone(),
+            source: source.clone(),
+            overridden: previous.clone(),
+          });
+        }
+      }
+      self.vars.insert(key.clone(), val);
+      self.origins.insert(key, source);
+    }
+    Ok(())
+  }
+
+  fn is_locked(&self, key: &str, source: &Source) -> bool {
+    self
+      .locked
+      .iter()
+      .any(|(kind, re)| kind == source.kind() && re.is_match(key))
+  }
+}
+
+// Pairs every key with its winning and overridden sources, ordered by key
+pub fn explain(processed: &ProcessedParameters, show_values: ShowValues) -> Vec<Explanation> {
+  let vars: BTreeMap<&String, &SecretValue> = processed.vars.iter().collect();
+  vars
+    .into_iter()
+    .map(|(key, val)| Explanation {
+      value: show_value(val.expose(), show_values),
+      source: processed.origins.get(key).cloned(),
+      overridden: processed
+        .overrides
+        .iter()
+        .filter(|o| &o.key == key)
+        .map(|o| o.overridden.clone())
+        .collect(),
+      key: key.to_owned(),
+    })
+    .collect()
+}
+
+pub fn as_explain_format(explanations: &[Explanation]) -> String {
+  let header = ["KEY", "VALUE", "SOURCE", "OVERRIDDEN"];
+  let rows: Vec<Vec<String>> = explanations
+    .iter()
+    .map(|e| {
+      let overridden: Vec<String> = e.overridden.iter().map(Source::to_string).collect();
+      vec![
+        e.key.to_owned(),
+        e.value.to_owned(),
+        e.source.as_ref().map(Source::to_string).unwrap_or_default(),
+        overridden.join(", "),
+      ]
+    })
+    .collect();
+  as_table(&header, &rows)
+}
+
+// Lists the source that set each key
+pub fn as_merge_report_format(origins: &HashMap<String, Source>) -> String {
+  let origins: BTreeMap<&String, &Source> = origins.iter().collect();
+  let rows: Vec<Vec<String>> = origins
+    .into_iter()
+    .map(|(key, source)| vec![key.to_owned(), source.to_string()])
+    .collect();
+  as_table(&["KEY", "SOURCE"], &rows)
+}
+
+/*
+    Renames every key following `transform`, failing when two keys would end
+    up with the same name
+*/
+pub fn transform_keys(
+  map: HashMap<String, String>,
+  transform: &KeyTransform,
+) -> Result<HashMap<String, String>, Error> {
+  let mut transformed = HashMap::<String, String>::with_capacity(map.len());
+  let mut sources = HashMap::<String, String>::with_capacity(map.len());
+  for (key, val) in sorted(map) {
+    let new_key = transform_key(&key, transform);
+    if let Some(other) = sources.insert(new_key.clone(), key.clone()) {
+      return Err(Error::Error(format!(
+        "Keys {other} and {key} would both be renamed to {new_key}"
+      )));
     }
+    transformed.insert(new_key, val);
+  }
+  Ok(transformed)
+}
+
+pub fn transform_key(key: &str, transform: &KeyTransform) -> String {
+  if let Some((_, to)) = transform.renames.iter().find(|(from, _)| from == key) {
+    return to.to_owned();
+  }
+  let key = match &transform.strip_prefix {
+    Some(prefix) => key.strip_prefix(prefix.as_str()).unwrap_or(key),
+    None => key,
   };
-  if let Some(app) = options.app {
-    map.entry("PROVIDE_APPLICATION".to_owned()).or_insert(app);
+  let key: String = match transform.replace_invalid {
+    Some(replacement) => key
+      .chars()
+      .map(|c| {
+        if c.is_ascii_alphanumeric() || c == '_' {
+          c
+        } else {
+          replacement
+        }
+      })
+      .collect(),
+    None => key.to_owned(),
   };
-  if let Some(target) = options.target {
-    map.entry("PROVIDE_TARGET".to_owned()).or_insert(target);
+  let key = match transform.case {
+    KeyCase::PRESERVE => key,
+    KeyCase::UPPER => key.to_uppercase(),
+    KeyCase::LOWER => key.to_lowercase(),
   };
-  Ok(map)
+  match &transform.prefix {
+    Some(prefix) => format!("{prefix}{key}"),
+    None => key,
+  }
 }
 
-async fn read_from_aws(path: String) -> Result<HashMap<String, String>, Error> {
+async fn aws_client() -> Client {
   let shared_config = aws_config::load_from_env().await;
-  let client = Client::new(&shared_config);
-  let mut map = HashMap::<String, String>::new();
+  Client::new(&shared_config)
+}
+
+// Parameters read for the pipeline, by their variable name
+#[derive(Default)]
+struct ReadParameters {
+  vars: HashMap<String, String>,
+  secure_keys: HashSet<String>,
+  names: HashMap<String, String>,
+  versions: HashMap<String, i64>,
+}
+
+impl ReadParameters {
+  fn insert(&mut self, key: &str, p: &Parameter) {
+    if let Some(ParameterType::SecureString) = p.r#type() {
+      self.secure_keys.insert(key.to_owned());
+    }
+    let name = p.name().unwrap_or_default().to_owned();
+    let val = p.value().unwrap_or_default().to_owned();
+    self.vars.insert(key.to_owned(), val);
+    self.names.insert(key.to_owned(), name);
+    self.versions.insert(key.to_owned(), p.version());
+  }
+}
+
+async fn read_from_aws(path: String, decrypt: bool) -> Result<ReadParameters, Error> {
+  let client = aws_client().await;
+  let mut read = ReadParameters::default();
+  for p in read_parameters_from_aws(&client, &path, decrypt).await? {
+    if let (Some(name), Some(_)) = (p.name(), p.value()) {
+      let key = extract_key_from_path(name)?;
+      read.insert(&key, &p);
+    }
+  }
+  Ok(read)
+}
+
+async fn read_parameters_from_aws(
+  client: &Client,
+  path: &str,
+  with_decryption: bool,
+) -> Result<Vec<Parameter>, Error> {
+  let mut acc = Vec::<Parameter>::new();
   let mut stream = client
     .get_parameters_by_path()
     .path(path)
     .recursive(false)
-    .with_decryption(false)
+    .with_decryption(with_decryption)
     .into_paginator()
     .send();
 
   while let Some(o) = stream.next().await {
     if let Some(parameters) = o?.parameters {
-      for p in parameters {
-        if let (Some(name), Some(val)) = (p.name(), p.value()) {
-          let key = extract_key_from_path(&name)?;
-          map.insert(key, val.to_string());
-        }
+      acc.extend(parameters);
+    }
+  }
+  Ok(acc)
+}
+
+async fn describe_parameters_from_aws(
+  client: &Client,
+  path: &str,
+) -> Result<Vec<ParameterMetadata>, Error> {
+  let mut acc = Vec::<ParameterMetadata>::new();
+  let filter = ParameterStringFilter::builder()
+    .key("Path")
+    .option("OneLevel")
+    .values(path)
+    .build();
+  let mut stream = client
+    .describe_parameters()
+    .parameter_filters(filter)
+    .into_paginator()
+    .send();
+
+  while let Some(o) = stream.next().await {
+    if let Some(parameters) = o?.parameters {
+      acc.extend(parameters);
+    }
+  }
+  Ok(acc)
+}
+
+// GetParameters and DeleteParameters accept at most 10 names per call
+const GET_PARAMETERS_BATCH_SIZE: usize = 10;
+const DELETE_PARAMETERS_BATCH_SIZE: usize = 10;
+
+async fn read_params_from_aws(specs: &[String]) -> Result<ReadParameters, Error> {
+  let specs = specs
+    .iter()
+    .map(|spec| parse_param_spec(spec))
+    .collect::<Result<Vec<ParamSpec>, Error>>()?;
+  let client = aws_client().await;
+  let mut read = ReadParameters::default();
+  for batch in specs.chunks(GET_PARAMETERS_BATCH_SIZE) {
+    let names: Vec<String> = batch.iter().map(|spec| spec.name.clone()).collect();
+    let output = client
+      .get_parameters()
+      .set_names(Some(names))
+      .with_decryption(true)
+      .send()
+      .await?;
+    if let Some(invalid) = output.invalid_parameters() {
+      if !invalid.is_empty() {
+        return Err(Error::InvalidPathError(format!(
+          "Parameters not found: {}",
+          invalid.join(", ")
+        )));
+      }
+    }
+    let parameters: HashMap<&str, &Parameter> = output
+      .parameters()
+      .unwrap_or_default()
+      .iter()
+      .filter_map(|p| p.name().map(|name| (name, p)))
+      .collect();
+    for spec in batch {
+      if let Some(p) = parameters.get(spec.name.as_str()) {
+        read.insert(&spec.key, p);
       }
     }
   }
-  Ok(map)
+  Ok(read)
+}
+
+// /shared/prod/SENTRY_DSN => SENTRY_DSN
+// /shared/prod/SENTRY_DSN=DSN => DSN
+pub fn parse_param_spec(spec: &str) -> Result<ParamSpec, Error> {
+  let (name, key) = match spec.find('=') {
+    Some(index) => (&spec[0..index], &spec[index + 1..]),
+    None => (spec, spec.rsplit(MAIN_SEPARATOR).next().unwrap_or_default()),
+  };
+  if name.is_empty() {
+    return Err(Error::BadFormat(format!("Invalid parameter {spec}")));
+  }
+  if key.is_empty() {
+    return Err(Error::BadFormat(format!(
+      "Invalid parameter {spec}, key has no length"
+    )));
+  }
+  Ok(ParamSpec {
+    name: name.to_owned(),
+    key: key.to_owned(),
+  })
 }
 
 pub fn read_pairs_from_files(
-  paths: &Vec<String>,
+  paths: &[String],
   use_base64: bool,
 ) -> Result<Vec<HashMap<String, String>>, Error> {
   paths
@@ -113,9 +497,30 @@ pub fn read_from_reader(
   reader: impl BufRead,
   use_base64: bool,
 ) -> Result<HashMap<String, String>, Error> {
-  let lines_iter = reader.lines().map(|line| parse_line(&line?, use_base64));
-  let lines: Vec<Option<Pair>> = lines_iter.collect::<Result<Vec<Option<Pair>>, Error>>()?;
-  Ok(lines.into_iter().filter_map(|p| p).collect())
+  let pairs = read_numbered_pairs_from_reader(reader, use_base64)?;
+  Ok(pairs.into_iter().map(|(_, pair)| pair).collect())
+}
+
+pub fn read_numbered_pairs_from_file(
+  path: &str,
+  use_base64: bool,
+) -> Result<Vec<(usize, Pair)>, Error> {
+  let reader = with_file(fs::canonicalize(path)?)?;
+  read_numbered_pairs_from_reader(reader, use_base64)
+}
+
+// Pairs with the line number they were read from, starting at 1
+pub fn read_numbered_pairs_from_reader(
+  reader: impl BufRead,
+  use_base64: bool,
+) -> Result<Vec<(usize, Pair)>, Error> {
+  let mut pairs = vec![];
+  for (index, line) in reader.lines().enumerate() {
+    if let Some(pair) = parse_line(&line?, use_base64)? {
+      pairs.push((index + 1, pair));
+    }
+  }
+  Ok(pairs)
 }
 
 fn parse_line(line: &str, use_base64: bool) -> Result<Option<Pair>, Error> {
@@ -159,6 +564,34 @@ fn extract_key_from_path(param_path: &str) -> Result<String, Error> {
   }
 }
 
+// Formats variables as asked for, systemd-dropin giving the contents of the drop-in
+pub fn format_vars(
+  format_config: &FormatConfig,
+  map: HashMap<String, String>,
+  secure_keys: &HashSet<String>,
+) -> Result<String, Error> {
+  let manifest = &format_config.manifest;
+  Ok(match format_config.format {
+    // Keys were already changed as --key-case says, like for every other format and run
+    Format::ENV => as_env_format_keeping_case(map, format_config.raw),
+    Format::EXPORT => as_export_format_keeping_case(map, format_config.raw),
+    Format::JSON => as_json_format(map)?,
+    Format::DOCKER => as_docker_format(map)?,
+    Format::K8S_SECRET | Format::K8S_CONFIGMAP if manifest.split => {
+      as_k8s_split_format(map, secure_keys, manifest)?
+    }
+    Format::K8S_SECRET => as_k8s_secret_format(map, manifest)?,
+    Format::K8S_CONFIGMAP => as_k8s_configmap_format(map, manifest)?,
+    Format::SH => as_sh_format(map)?,
+    Format::FISH => as_fish_format(map)?,
+    Format::PWSH => as_pwsh_format(map)?,
+    Format::GITHUB_ENV => as_github_env_format(map),
+    Format::GITLAB_DOTENV => as_gitlab_dotenv_format(map)?,
+    Format::SYSTEMD => as_systemd_format(map)?,
+    Format::SYSTEMD_DROPIN => as_systemd_dropin_format(map)?,
+  })
+}
+
 /*
     Outputs String with the following format:
     WHAT="EVERS"\n
@@ -167,10 +600,19 @@ fn extract_key_from_path(param_path: &str) -> Result<String, Error> {
     Intended to output to a file or to be evaled
 */
 pub fn as_env_format(map: HashMap<String, String>, raw: bool) -> String {
+  env_format(map, raw, str::to_uppercase)
+}
+
+// Same as as_env_format, printing keys as they are
+pub fn as_env_format_keeping_case(map: HashMap<String, String>, raw: bool) -> String {
+  env_format(map, raw, str::to_owned)
+}
+
+fn env_format(map: HashMap<String, String>, raw: bool, key: fn(&str) -> String) -> String {
   let lines: Vec<String> = map
     .into_iter()
     .map(|(k, v)| {
-      let key = k.to_uppercase();
+      let key = key(&k);
       let val = if raw { v } else { base64::encode(&v) };
       format!("{key}={val}\n")
     })
@@ -179,10 +621,19 @@ pub fn as_env_format(map: HashMap<String, String>, raw: bool) -> String {
 }
 
 pub fn as_export_format(map: HashMap<String, String>, raw: bool) -> String {
+  export_format(map, raw, str::to_uppercase)
+}
+
+// Same as as_export_format, printing keys as they are
+pub fn as_export_format_keeping_case(map: HashMap<String, String>, raw: bool) -> String {
+  export_format(map, raw, str::to_owned)
+}
+
+fn export_format(map: HashMap<String, String>, raw: bool, key: fn(&str) -> String) -> String {
   let lines: Vec<String> = map
     .into_iter()
     .map(|(k, v)| {
-      let key = k.to_uppercase();
+      let key = key(&k);
       if raw {
         let val = base64::encode(&v);
         format!("export {key}={val}\n")
@@ -196,77 +647,2275 @@ pub fn as_export_format(map: HashMap<String, String>, raw: bool) -> String {
 }
 
 lazy_static! {
-  static ref RE: Regex = Regex::new(r#"([$`"!\)\\])"#).unwrap();
+  static ref SHELL_NAME_RE: Regex = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
 }
 
-// Escape $`"!)\ for use in bash
-pub fn escape_for_bash(val: &str) -> String {
-  RE.replace_all(val, "\\$1").into_owned()
+// Formats one line per pair, rejecting keys that are not valid shell variable names
+fn as_shell_format(
+  map: HashMap<String, String>,
+  line: impl Fn(&str, &str) -> String,
+) -> Result<String, Error> {
+  let lines: Vec<String> = sorted(map)
+    .into_iter()
+    .map(|(key, val)| match SHELL_NAME_RE.is_match(&key) {
+      true => Ok(line(&key, &val)),
+      false => Err(Error::BadFormat(format!(
+        "Key {key:?} is not a valid shell variable name"
+      ))),
+    })
+    .collect::<Result<Vec<String>, Error>>()?;
+  Ok(lines.join(""))
 }
 
-pub fn merge_with_given(
-  lines: &Vec<String>,
-  use_base64: bool,
-) -> Result<HashMap<String, String>, Error> {
-  let map = lines
-    .iter()
-    .map(|line| parse_line(line, use_base64))
-    .collect::<Result<Vec<Option<Pair>>, Error>>()?
+/*
+    Outputs String for any POSIX shell, values single quoted:
+    export FOO='it'\''s'\n
+*/
+pub fn as_sh_format(map: HashMap<String, String>) -> Result<String, Error> {
+  as_shell_format(map, |key, val| {
+    format!("export {key}={}\n", escape_for_sh(val))
+  })
+}
+
+/*
+    Outputs String for fish:
+    set -gx FOO 'it\'s'\n
+*/
+pub fn as_fish_format(map: HashMap<String, String>) -> Result<String, Error> {
+  as_shell_format(map, |key, val| {
+    format!("set -gx {key} {}\n", escape_for_fish(val))
+  })
+}
+
+/*
+    Outputs String for PowerShell:
+    $env:FOO = 'it''s'\n
+*/
+pub fn as_pwsh_format(map: HashMap<String, String>) -> Result<String, Error> {
+  as_shell_format(map, |key, val| {
+    format!("$env:{key} = {}\n", escape_for_pwsh(val))
+  })
+}
+
+// Nothing is special inside single quotes, so only ' needs to close, escape and reopen
+pub fn escape_for_sh(val: &str) -> String {
+  format!("'{}'", val.replace('\'', r"'\''"))
+}
+
+// Inside fish single quotes only \ and ' are special
+pub fn escape_for_fish(val: &str) -> String {
+  format!("'{}'", val.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+// PowerShell treats typographic single quotes as ' too, each is escaped by doubling it
+pub fn escape_for_pwsh(val: &str) -> String {
+  let escaped: String = val
+    .chars()
+    .flat_map(|c| match c {
+      '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}' => vec![c, c],
+      _ => vec![c],
+    })
+    .collect();
+  format!("'{escaped}'")
+}
+
+/*
+    Outputs String for appending to $GITHUB_ENV, multi-line values using the
+    heredoc syntax with a delimiter that does not occur in the value:
+    FOO=bar\n
+    CERT<<ghadelimiter_0c1f9e2b5d4a\n
+    line 1\n
+    line 2\n
+    ghadelimiter_0c1f9e2b5d4a\n
+*/
+pub fn as_github_env_format(map: HashMap<String, String>) -> String {
+  let lines: Vec<String> = sorted(map)
     .into_iter()
-    .filter_map(|p| p)
+    .map(|(key, val)| {
+      if val.contains(['\n', '\r']) {
+        let delimiter = github_delimiter(&key, &val);
+        format!("{key}<<{delimiter}\n{val}\n{delimiter}\n")
+      } else {
+        format!("{key}={val}\n")
+      }
+    })
     .collect();
-  Ok(map)
+  lines.join("")
 }
 
-pub fn merge_with_env(line: &str, use_base64: bool) -> Result<(String, String), Error> {
-  let key = line.to_string();
-  let maybe_encoded_val = env::var(&key)?;
-  let val = if use_base64 {
-    String::from_utf8(base64::decode(&maybe_encoded_val)?)?
-  } else {
-    maybe_encoded_val
-  };
-  Ok((key, val))
+fn github_delimiter(key: &str, val: &str) -> String {
+  let mut seed = format!("{key}={val}");
+  loop {
+    let delimiter = format!("ghadelimiter_{}", &hash_value(&seed)["hmac:".len()..]);
+    if !val.contains(&delimiter) {
+      return delimiter;
+    }
+    seed.push('_');
+  }
 }
 
-pub fn merge_with_commands(
-  paths: &Vec<String>,
-  vars: &HashMap<String, String>,
-) -> Result<Vec<HashMap<String, String>>, Error> {
-  paths
+/*
+    Outputs String of GitHub workflow commands masking each line of the values
+    of `secure_keys` in logs:
+    ::add-mask::secret\n
+*/
+pub fn as_github_mask_format(
+  map: &HashMap<String, String>,
+  secure_keys: &HashSet<String>,
+) -> String {
+  let mut values: Vec<&str> = map
     .iter()
-    .map(|path| merge_with_command(path, vars))
+    .filter(|(key, _)| secure_keys.contains(*key))
+    .flat_map(|(_, val)| val.lines())
+    .filter(|line| !line.trim().is_empty())
+    .collect();
+  values.sort();
+  values.dedup();
+  let lines: Vec<String> = values
+    .into_iter()
+    .map(|val| {
+      let escaped = val.replace('%', "%25").replace('\r', "%0D");
+      format!("::add-mask::{escaped}\n")
+    })
+    .collect();
+  lines.join("")
+}
+
+lazy_static! {
+  static ref GITLAB_KEY_RE: Regex = Regex::new(r"^[A-Za-z0-9_]+$").unwrap();
+}
+
+/*
+    Outputs String for a GitLab dotenv artifact report, values taken literally:
+    FOO=bar\n
+*/
+pub fn as_gitlab_dotenv_format(map: HashMap<String, String>) -> Result<String, Error> {
+  let lines: Vec<String> = sorted(map)
+    .into_iter()
+    .map(|(key, val)| {
+      if !GITLAB_KEY_RE.is_match(&key) {
+        return Err(Error::BadFormat(format!(
+          "Key {key:?} is not valid in a GitLab dotenv report"
+        )));
+      }
+      if val.contains(['\n', '\r']) {
+        return Err(Error::BadFormat(format!(
+          "Value of {key} contains a newline, which GitLab dotenv reports do not support"
+        )));
+      }
+      Ok(format!("{key}={val}\n"))
+    })
+    .collect::<Result<Vec<String>, Error>>()?;
+  Ok(lines.join(""))
+}
+
+/*
+    Outputs String for a systemd EnvironmentFile, values double quoted:
+    FOO="say \\"hi\\" for \\$5"\n
+*/
+pub fn as_systemd_format(map: HashMap<String, String>) -> Result<String, Error> {
+  as_shell_format(map, |key, val| {
+    format!("{key}={}\n", escape_for_systemd_env_file(val))
+  })
+}
+
+/*
+    Outputs String for a systemd unit drop-in:
+    [Service]\n
+    Environment="FOO=bar"\n
+*/
+pub fn as_systemd_dropin_format(map: HashMap<String, String>) -> Result<String, Error> {
+  let environment = as_shell_format(map, |key, val| {
+    format!("Environment=\"{key}={}\"\n", escape_for_systemd_unit(val))
+  })?;
+  Ok(format!("[Service]\n{environment}"))
+}
+
+// Inside double quotes an EnvironmentFile only unescapes \", \\, \` and \$
+pub fn escape_for_systemd_env_file(val: &str) -> String {
+  let escaped: String = val
+    .chars()
+    .flat_map(|c| match c {
+      '"' | '\\' | '`' | '$' => vec!['\\', c],
+      _ => vec![c],
+    })
+    .collect();
+  format!("\"{escaped}\"")
+}
+
+// Unit files unescape C style escapes and expand % specifiers
+pub fn escape_for_systemd_unit(val: &str) -> String {
+  val
+    .chars()
+    .map(|c| match c {
+      '"' => String::from("\\\""),
+      '\\' => String::from("\\\\"),
+      '\n' => String::from("\\n"),
+      '\r' => String::from("\\r"),
+      '\t' => String::from("\\t"),
+      '%' => String::from("%%"),
+      _ => c.to_string(),
+    })
     .collect()
 }
 
-pub fn merge_with_command(
-  path: &String,
-  vars: &HashMap<String, String>,
-) -> Result<HashMap<String, String>, Error> {
-  let path_buf = fs::canonicalize(path)?;
-  let mut command = Command::new(path_buf);
-  command.envs(vars);
-  let output = command.output()?;
-  match output.status.code() {
-    Some(0) => read_from_reader(BufReader::new(Cursor::new(output.stdout)), true),
-    Some(_) => Err(Error::Error(String::from_utf8(output.stderr)?)),
-    None => Err(Error::Error(format!("Terminated by signal"))),
+pub const SYSTEMD_DIR: &str = "/etc/systemd/system";
+
+// Writes the drop-in to <dir>/<unit>.d/provide.conf, returning its path
+pub fn write_systemd_dropin(
+  map: HashMap<String, String>,
+  unit: &str,
+  dir: Option<&str>,
+) -> Result<PathBuf, Error> {
+  if unit.is_empty() || unit.contains(MAIN_SEPARATOR) {
+    return Err(Error::BadFormat(format!("Invalid systemd unit {unit:?}")));
   }
+  let contents = as_systemd_dropin_format(map)?;
+  let path = PathBuf::from(dir.unwrap_or(SYSTEMD_DIR))
+    .join(format!("{unit}.d"))
+    .join("provide.conf");
+  write_private_file(&path, &contents)?;
+  Ok(path)
 }
 
-pub fn run(run_config: RunConfig, vars: HashMap<String, String>) -> Result<(), Error> {
-  let filename = run_config.cmd;
-  let mut command = Command::new(&filename);
-  command.envs(vars);
-  command.stdout(Stdio::inherit());
-  command.stderr(Stdio::inherit());
-  command.args(run_config.args);
-  match command.status() {
-    Ok(status) => match status.code() {
-      Some(0) => Ok(()),
-      Some(code) => Err(Error::Error(format!("Exit code {code}"))),
-      None => Err(Error::Error(format!("Terminated by signal"))),
-    },
-    Err(err) => Err(Error::IOError(err)),
+// Writes `contents` readable and writable by the owner only, creating parent directories
+pub fn write_private_file(path: &Path, contents: &str) -> Result<(), Error> {
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  write_output(path, contents, &OutputConfig::default())
+}
+
+pub const DEFAULT_OUTPUT_MODE: u32 = 0o600;
+
+/*
+    Writes `contents` to a temporary file next to `path` with the configured mode
+    and ownership, then renames it over `path` so readers never see a partial file
+    or one with looser permissions.
+
+    Refuses directories anyone can write to, where another user could swap the
+    file out from under us.
+*/
+pub fn write_output(path: &Path, contents: &str, output: &OutputConfig) -> Result<(), Error> {
+  let file_name = path
+    .file_name()
+    .ok_or_else(|| Error::UnsafePathError(format!("{} is not a file", path.display())))?;
+  let dir = match path.parent() {
+    Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+    _ => PathBuf::from("."),
+  };
+  #[cfg(unix)]
+  if fs::metadata(&dir)?.permissions().mode() & 0o002 != 0 {
+    return Err(Error::UnsafePathError(format!(
+      "Refusing to write into world-writable directory {}",
+      dir.display()
+    )));
+  }
+  let temp_path = dir.join(format!(
+    ".{}.{}.tmp",
+    file_name.to_string_lossy(),
+    std::process::id()
+  ));
+  let result =
+    write_temp_file(&temp_path, contents, output).and_then(|_| Ok(fs::rename(&temp_path, path)?));
+  if result.is_err() {
+    let _ = fs::remove_file(&temp_path);
+  }
+  result
+}
+
+fn write_temp_file(path: &Path, contents: &str, output: &OutputConfig) -> Result<(), Error> {
+  let mut options = fs::OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  options.mode(output.mode.unwrap_or(DEFAULT_OUTPUT_MODE));
+  let mut file = options.open(path)?;
+  // The umask may have cleared bits of the requested mode
+  #[cfg(unix)]
+  file.set_permissions(fs::Permissions::from_mode(
+    output.mode.unwrap_or(DEFAULT_OUTPUT_MODE),
+  ))?;
+  #[cfg(unix)]
+  if output.owner.is_some() || output.group.is_some() {
+    let uid = output.owner.as_deref().map(resolve_uid).transpose()?;
+    let gid = output.group.as_deref().map(resolve_gid).transpose()?;
+    std::os::unix::fs::fchown(&file, uid, gid)?;
+  }
+  file.write_all(contents.as_bytes())?;
+  file.sync_all()?;
+  Ok(())
+}
+
+// Numeric ids are used as is, names are looked up through NSS like any other program does
+#[cfg(unix)]
+fn resolve_uid(name: &str) -> Result<u32, Error> {
+  if let Ok(id) = name.parse::<u32>() {
+    return Ok(id);
+  }
+  match User::from_name(name) {
+    Ok(Some(user)) => Ok(user.uid.as_raw()),
+    Ok(None) => Err(Error::Error(format!("No user named {name}"))),
+    Err(errno) => Err(Error::Error(format!(
+      "Could not look up user {name}: {errno}"
+    ))),
+  }
+}
+
+#[cfg(unix)]
+fn resolve_gid(name: &str) -> Result<u32, Error> {
+  if let Ok(id) = name.parse::<u32>() {
+    return Ok(id);
+  }
+  match Group::from_name(name) {
+    Ok(Some(group)) => Ok(group.gid.as_raw()),
+    Ok(None) => Err(Error::Error(format!("No group named {name}"))),
+    Err(errno) => Err(Error::Error(format!(
+      "Could not look up group {name}: {errno}"
+    ))),
+  }
+}
+
+pub fn parse_mode(mode: &str) -> Result<u32, Error> {
+  match u32::from_str_radix(mode, 8) {
+    Ok(parsed) if parsed <= 0o777 => Ok(parsed),
+    _ => Err(Error::BadFormat(format!(
+      "Invalid mode {mode}, expected e.g. 0600"
+    ))),
+  }
+}
+
+async fn read_history_from_aws(
+  client: &Client,
+  name: &str,
+) -> Result<Vec<ParameterHistory>, Error> {
+  let mut acc = Vec::<ParameterHistory>::new();
+  let mut stream = client
+    .get_parameter_history()
+    .name(name)
+    .with_decryption(true)
+    .into_paginator()
+    .send();
+
+  while let Some(o) = stream.next().await {
+    if let Some(parameters) = o?.parameters {
+      acc.extend(parameters);
+    }
+  }
+  acc.sort_by_key(|h| h.version());
+  Ok(acc)
+}
+
+// All versions of a parameter, oldest first
+pub async fn parameter_history(name: &str) -> Result<Vec<ParameterHistory>, Error> {
+  let client = aws_client().await;
+  read_history_from_aws(&client, name).await
+}
+
+pub fn as_history_format(
+  history: &[ParameterHistory],
+  show_values: ShowValues,
+  report_format: ReportFormat,
+) -> Result<String, Error> {
+  let entries: Vec<HistoryEntry> = history
+    .iter()
+    .map(|h| HistoryEntry {
+      version: h.version(),
+      last_modified_date: h.last_modified_date().map(format_date_time),
+      last_modified_user: h.last_modified_user().map(String::from),
+      labels: h.labels().unwrap_or_default().to_vec(),
+      value: show_value(h.value().unwrap_or_default(), show_values),
+    })
+    .collect();
+  match report_format {
+    ReportFormat::JSON => Ok(format!("{}\n", serde_json::to_string_pretty(&entries)?)),
+    ReportFormat::TABLE => {
+      let header = ["VERSION", "LAST MODIFIED", "MODIFIED BY", "LABELS", "VALUE"];
+      let rows: Vec<Vec<String>> = entries
+        .into_iter()
+        .map(|entry| {
+          vec![
+            entry.version.to_string(),
+            entry.last_modified_date.unwrap_or_default(),
+            entry.last_modified_user.unwrap_or_default(),
+            entry.labels.join(","),
+            entry.value,
+          ]
+        })
+        .collect();
+      Ok(as_table(&header, &rows))
+    }
+  }
+}
+
+// The version a parameter had at `point`, given its history oldest first
+pub fn select_version(
+  history: &[ParameterHistory],
+  point: RollbackPoint,
+) -> Option<&ParameterHistory> {
+  match point {
+    RollbackPoint::VERSION(version) => history.iter().find(|h| h.version() == version),
+    RollbackPoint::DATE(date) => history.iter().rev().find(|h| match h.last_modified_date() {
+      Some(modified) => {
+        (modified.secs(), modified.subsec_nanos()) <= (date.secs(), date.subsec_nanos())
+      }
+      None => false,
+    }),
+  }
+}
+
+/*
+    Re-puts the value each key had at `point`, for one key or every key under
+    `path`. When rolling back every key, the ones that did not exist at
+    `point` are returned as created. Parameters deleted since cannot be
+    restored as Parameter Store deletes their history with them.
+*/
+pub async fn plan_rollback(
+  path: &str,
+  key: Option<&str>,
+  point: RollbackPoint,
+) -> Result<RollbackPlan, Error> {
+  let client = aws_client().await;
+  let names: Vec<String> = match key {
+    Some(key) => vec![format!("{}/{}", path.trim_end_matches('/'), key)],
+    None => read_parameters_from_aws(&client, path, false)
+      .await?
+      .iter()
+      .filter_map(|p| p.name().map(String::from))
+      .collect(),
+  };
+  let mut promotions = Vec::<Promotion>::new();
+  let mut created = Vec::<String>::new();
+  for name in names {
+    let history = read_history_from_aws(&client, &name).await?;
+    let selected = match (select_version(&history, point), key) {
+      (Some(selected), _) => selected,
+      (None, None) => {
+        created.push(name);
+        continue;
+      }
+      (None, Some(_)) => {
+        let at = match point {
+          RollbackPoint::VERSION(version) => format!("version {version}"),
+          RollbackPoint::DATE(date) => format_date_time(&date),
+        };
+        return Err(Error::Error(format!("No version of {name} found at {at}")));
+      }
+    };
+    let current = history.last().and_then(|h| h.value());
+    if let (Some(val), Some(parameter_type)) = (selected.value(), selected.r#type()) {
+      if current == Some(val) {
+        continue;
+      }
+      promotions.push(Promotion {
+        key: extract_key_from_path(&name)?,
+        name: name.clone(),
+        value: SecretValue::from(val),
+        parameter_type: parameter_type.clone(),
+        key_id: match parameter_type {
+          ParameterType::SecureString => selected.key_id().map(String::from),
+          _ => None,
+        },
+        overwrite: true,
+      });
+    }
+  }
+  promotions.sort_by(|a, b| a.key.cmp(&b.key));
+  created.sort();
+  Ok(RollbackPlan {
+    promotions,
+    created,
+  })
+}
+
+/*
+    Outputs String with the promotions, then the created parameters:
+    ~ DB_PASSWORD (SecureString)\n
+    - /app/production/NEW_KEY (created since)\n
+    Created parameters are marked as kept unless `delete_created`.
+*/
+pub fn as_rollback_format(plan: &RollbackPlan, delete_created: bool) -> String {
+  let created: Vec<String> = plan
+    .created
+    .iter()
+    .map(|name| match delete_created {
+      true => format!("- {name} (created since)\n"),
+      false => format!("  {name} (created since, kept without --delete-created)\n"),
+    })
+    .collect();
+  as_promotion_format(&plan.promotions) + &created.join("")
+}
+
+pub fn parse_date_time(val: &str) -> Result<DateTime, Error> {
+  DateTime::from_str(val, DateTimeFormat::DateTime).map_err(|_| {
+    Error::BadFormat(format!(
+      "Invalid date {val}, expected e.g. 2022-05-01T10:00:00Z"
+    ))
+  })
+}
+
+pub async fn list_parameters(path: &str) -> Result<Vec<ParameterInfo>, Error> {
+  let client = aws_client().await;
+  let mut infos = describe_parameters_from_aws(&client, path)
+    .await?
+    .iter()
+    .filter_map(|m| m.name().map(|name| (name, m)))
+    .map(|(name, m)| {
+      Ok(ParameterInfo {
+        key: extract_key_from_path(name)?,
+        parameter_type: m
+          .r#type()
+          .map(|t| t.as_str())
+          .unwrap_or_default()
+          .to_owned(),
+        version: m.version(),
+        last_modified_date: m.last_modified_date().map(format_date_time),
+        last_modified_user: m.last_modified_user().map(String::from),
+        tier: m.tier().map(|t| t.as_str().to_owned()),
+        description: m.description().map(String::from),
+      })
+    })
+    .collect::<Result<Vec<ParameterInfo>, Error>>()?;
+  infos.sort_by(|a, b| a.key.cmp(&b.key));
+  Ok(infos)
+}
+
+fn format_date_time(date_time: &DateTime) -> String {
+  date_time
+    .fmt(DateTimeFormat::DateTime)
+    .unwrap_or_else(|_| date_time.secs().to_string())
+}
+
+pub fn as_list_format(
+  infos: &[ParameterInfo],
+  report_format: ReportFormat,
+) -> Result<String, Error> {
+  match report_format {
+    ReportFormat::JSON => Ok(format!("{}\n", serde_json::to_string_pretty(infos)?)),
+    ReportFormat::TABLE => {
+      let header = [
+        "KEY",
+        "TYPE",
+        "VERSION",
+        "LAST MODIFIED",
+        "MODIFIED BY",
+        "TIER",
+        "DESCRIPTION",
+      ];
+      let rows: Vec<Vec<String>> = infos
+        .iter()
+        .map(|info| {
+          vec![
+            info.key.clone(),
+            info.parameter_type.clone(),
+            info.version.to_string(),
+            info.last_modified_date.clone().unwrap_or_default(),
+            info.last_modified_user.clone().unwrap_or_default(),
+            info.tier.clone().unwrap_or_default(),
+            info.description.clone().unwrap_or_default(),
+          ]
+        })
+        .collect();
+      Ok(as_table(&header, &rows))
+    }
+  }
+}
+
+// Left aligns each column to its widest cell
+pub fn as_table(header: &[&str], rows: &[Vec<String>]) -> String {
+  let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+  for row in rows {
+    for (i, cell) in row.iter().enumerate() {
+      widths[i] = widths[i].max(cell.chars().count());
+    }
+  }
+  let header: Vec<String> = header.iter().map(|h| h.to_string()).collect();
+  let lines: Vec<String> = std::iter::once(&header)
+    .chain(rows.iter())
+    .map(|row| {
+      let cells: Vec<String> = row
+        .iter()
+        .enumerate()
+        .map(|(i, cell)| format!("{cell:<width$}", width = widths[i]))
+        .collect();
+      format!("{}\n", cells.join("  ").trim_end())
+    })
+    .collect();
+  lines.join("")
+}
+
+// Reads decrypted parameters under `from_path`, with their type and KMS key,
+// and pairs them with their new names under `to_path`
+pub async fn plan_promotion(options: &PromoteOptions) -> Result<Vec<Promotion>, Error> {
+  let (only, except) = compile_key_filter(&options.key_filter)?;
+  let client = aws_client().await;
+  let key_ids: HashMap<String, String> = describe_parameters_from_aws(&client, &options.from_path)
+    .await?
+    .into_iter()
+    .filter_map(|m| Some((m.name()?.to_owned(), m.key_id()?.to_owned())))
+    .collect();
+  let existing: HashSet<String> = read_parameters_from_aws(&client, &options.to_path, false)
+    .await?
+    .iter()
+    .filter_map(|p| p.name())
+    .map(extract_key_from_path)
+    .collect::<Result<HashSet<String>, Error>>()?;
+  let mut promotions = Vec::<Promotion>::new();
+  for p in read_parameters_from_aws(&client, &options.from_path, true).await? {
+    if let (Some(name), Some(val), Some(parameter_type)) = (p.name(), p.value(), p.r#type()) {
+      let key = extract_key_from_path(name)?;
+      if !is_key_allowed(&key, &only, &except) {
+        continue;
+      }
+      let key_id = match parameter_type {
+        ParameterType::SecureString => key_ids.get(name).cloned(),
+        _ => None,
+      };
+      promotions.push(Promotion {
+        name: format!("{}/{}", options.to_path.trim_end_matches('/'), key),
+        value: SecretValue::from(val),
+        parameter_type: parameter_type.clone(),
+        key_id,
+        overwrite: existing.contains(&key),
+        key,
+      });
+    }
+  }
+  promotions.sort_by(|a, b| a.key.cmp(&b.key));
+  Ok(promotions)
+}
+
+pub async fn promote(promotions: &[Promotion]) -> Result<(), Error> {
+  let client = aws_client().await;
+  for promotion in promotions {
+    client
+      .put_parameter()
+      .name(&promotion.name)
+      .value(promotion.value.expose())
+      .r#type(promotion.parameter_type.clone())
+      .set_key_id(promotion.key_id.clone())
+      .overwrite(promotion.overwrite)
+      .send()
+      .await?;
+  }
+  Ok(())
+}
+
+/*
+    Outputs String with the following format:
+    + NEW_KEY (String)\n
+    ~ EXISTING_KEY (SecureString)\n
+*/
+pub fn as_promotion_format(promotions: &[Promotion]) -> String {
+  let lines: Vec<String> = promotions
+    .iter()
+    .map(|promotion| {
+      let marker = if promotion.overwrite { "~" } else { "+" };
+      let parameter_type = promotion.parameter_type.as_str();
+      format!("{marker} {} ({parameter_type})\n", promotion.key)
+    })
+    .collect();
+  lines.join("")
+}
+
+// Names of parameters under `path` whose keys are missing from `vars`
+pub async fn plan_prune(path: &str, vars: &HashMap<String, String>) -> Result<Vec<String>, Error> {
+  let client = aws_client().await;
+  let mut names = Vec::<String>::new();
+  for p in read_parameters_from_aws(&client, path, false).await? {
+    if let Some(name) = p.name() {
+      if !vars.contains_key(&extract_key_from_path(name)?) {
+        names.push(name.to_owned());
+      }
+    }
+  }
+  names.sort();
+  Ok(names)
+}
+
+// Fails listing the names AWS did not delete, after trying every batch
+pub async fn delete_parameters(names: &[String]) -> Result<(), Error> {
+  let client = aws_client().await;
+  let mut invalid = Vec::<String>::new();
+  for batch in names.chunks(DELETE_PARAMETERS_BATCH_SIZE) {
+    let output = client
+      .delete_parameters()
+      .set_names(Some(batch.to_vec()))
+      .send()
+      .await?;
+    invalid.extend(
+      output
+        .invalid_parameters()
+        .unwrap_or_default()
+        .iter()
+        .cloned(),
+    );
+  }
+  match invalid.is_empty() {
+    true => Ok(()),
+    false => Err(Error::InvalidPathError(format!(
+      "Parameters not deleted: {}",
+      invalid.join(", ")
+    ))),
+  }
+}
+
+pub fn compile_key_filter(filter: &KeyFilter) -> Result<(Vec<Regex>, Vec<Regex>), Error> {
+  let compile = |patterns: &Option<Vec<String>>| -> Result<Vec<Regex>, Error> {
+    patterns
+      .iter()
+      .flatten()
+      .map(|pattern| Ok(Regex::new(pattern)?))
+      .collect()
+  };
+  Ok((compile(&filter.only)?, compile(&filter.except)?))
+}
+
+// No `only` patterns allows every key not matched by an `except` pattern
+pub fn is_key_allowed(key: &str, only: &[Regex], except: &[Regex]) -> bool {
+  (only.is_empty() || only.iter().any(|re| re.is_match(key)))
+    && !except.iter().any(|re| re.is_match(key))
+}
+
+/*
+    Keeps the keys allowed by `filter`, also returning the patterns that
+    matched none of the given keys
+*/
+pub fn filter_keys(
+  map: HashMap<String, String>,
+  filter: &KeyFilter,
+) -> Result<(HashMap<String, String>, Vec<String>), Error> {
+  let (only, except) = compile_key_filter(filter)?;
+  let unmatched_patterns = only
+    .iter()
+    .chain(except.iter())
+    .filter(|re| !map.keys().any(|key| re.is_match(key)))
+    .map(|re| re.as_str().to_owned())
+    .collect();
+  let map = map
+    .into_iter()
+    .filter(|(key, _)| is_key_allowed(key, &only, &except))
+    .collect();
+  Ok((map, unmatched_patterns))
+}
+
+/*
+    Reads a key filter from lines of only=REGEX or except=REGEX, ignoring
+    empty lines and lines starting with #
+*/
+pub fn read_key_filter_file(path: &str) -> Result<KeyFilter, Error> {
+  read_key_filter(with_file(fs::canonicalize(path)?)?)
+}
+
+pub fn read_key_filter(reader: impl BufRead) -> Result<KeyFilter, Error> {
+  let mut filter = KeyFilter::default();
+  for line in reader.lines() {
+    let line = line?;
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let (patterns, pattern) = match line.split_once('=') {
+      Some(("only", pattern)) => (&mut filter.only, pattern),
+      Some(("except", pattern)) => (&mut filter.except, pattern),
+      _ => {
+        return Err(Error::BadFormat(format!(
+          "Invalid filter {line}, expected only=REGEX or except=REGEX"
+        )))
+      }
+    };
+    patterns
+      .get_or_insert_with(Vec::new)
+      .push(pattern.to_owned());
+  }
+  Ok(filter)
+}
+
+// Keys only in `right` are added, keys only in `left` are removed
+pub fn diff(left: &HashMap<String, String>, right: &HashMap<String, String>) -> Vec<Difference> {
+  let keys: BTreeSet<&String> = left.keys().chain(right.keys()).collect();
+  keys
+    .into_iter()
+    .filter_map(|key| match (left.get(key), right.get(key)) {
+      (Some(from), Some(to)) if from != to => Some(Difference::Changed(
+        key.to_owned(),
+        from.to_owned(),
+        to.to_owned(),
+      )),
+      (Some(from), None) => Some(Difference::Removed(key.to_owned(), from.to_owned())),
+      (None, Some(to)) => Some(Difference::Added(key.to_owned(), to.to_owned())),
+      _ => None,
+    })
+    .collect()
+}
+
+/*
+    Outputs String with the following format:
+    + ADDED=***\n
+    - REMOVED=***\n
+    ~ CHANGED=*** => ***\n
+*/
+pub fn as_diff_format(differences: &[Difference], show_values: ShowValues) -> String {
+  let show = |val: &str| show_value(val, show_values);
+  let lines: Vec<String> = differences
+    .iter()
+    .map(|difference| match difference {
+      Difference::Added(key, val) => format!("+ {key}={}\n", show(val)),
+      Difference::Removed(key, val) => format!("- {key}={}\n", show(val)),
+      Difference::Changed(key, from, to) => {
+        format!("~ {key}={} => {}\n", show(from), show(to))
+      }
+    })
+    .collect();
+  lines.join("")
+}
+
+pub fn show_value(val: &str, show_values: ShowValues) -> String {
+  match show_values {
+    ShowValues::MASKED => String::from("***"),
+    ShowValues::HASH => hash_value(val),
+    ShowValues::FULL => val.to_owned(),
+  }
+}
+
+lazy_static! {
+  // Random for every run so that short fingerprints cannot be brute-forced offline
+  static ref FINGERPRINT_KEY: [u8; 32] = {
+    let mut key = [0u8; 32];
+    getrandom::getrandom(&mut key).expect("no randomness available");
+    key
+  };
+}
+
+/*
+    Short fingerprint of a value that can be compared without revealing it.
+    Keyed per run, so fingerprints only compare within the same output.
+*/
+pub fn hash_value(val: &str) -> String {
+  let mut mac =
+    Hmac::<Sha256>::new_from_slice(FINGERPRINT_KEY.as_slice()).expect("HMAC takes any key length");
+  mac.update(val.as_bytes());
+  let hex: String = mac
+    .finalize()
+    .into_bytes()
+    .iter()
+    .map(|b| format!("{b:02x}"))
+    .collect();
+  format!("hmac:{}", &hex[0..12])
+}
+
+// A JSON object of string values, sorted by key
+pub fn as_json_format(map: HashMap<String, String>) -> Result<String, Error> {
+  let sorted: BTreeMap<String, String> = map.into_iter().collect();
+  Ok(format!("{}\n", serde_json::to_string_pretty(&sorted)?))
+}
+
+/*
+    Outputs String in docker's --env-file format, values taken literally:
+    FOO=bar baz\n
+
+    Docker has no quoting or escaping, so keys it would misread and values
+    spanning lines are rejected
+*/
+pub fn as_docker_format(map: HashMap<String, String>) -> Result<String, Error> {
+  let lines: Vec<String> = sorted(map)
+    .into_iter()
+    .map(|(key, val)| {
+      if key.is_empty() || key.starts_with('#') || key.contains(|c: char| c.is_whitespace()) {
+        return Err(Error::BadFormat(format!(
+          "Key {key:?} is not valid in a docker env file"
+        )));
+      }
+      if val.contains(['\n', '\r']) {
+        return Err(Error::BadFormat(format!(
+          "Value of {key} contains a newline, which docker env files do not support"
+        )));
+      }
+      Ok(format!("{key}={val}\n"))
+    })
+    .collect::<Result<Vec<String>, Error>>()?;
+  Ok(lines.join(""))
+}
+
+/*
+    Outputs String with a Kubernetes Secret manifest, values base64 encoded:
+    apiVersion: v1\n
+    kind: Secret\n
+    ...
+    data:\n
+      "FOO": "YmFy"\n
+*/
+pub fn as_k8s_secret_format(
+  map: HashMap<String, String>,
+  manifest: &ManifestConfig,
+) -> Result<String, Error> {
+  let data: Vec<(String, String)> = sorted(map)
+    .into_iter()
+    .map(|(key, val)| (key, base64::encode(&val)))
+    .collect();
+  as_k8s_manifest("Secret", Some("Opaque"), data, manifest)
+}
+
+pub fn as_k8s_configmap_format(
+  map: HashMap<String, String>,
+  manifest: &ManifestConfig,
+) -> Result<String, Error> {
+  as_k8s_manifest("ConfigMap", None, sorted(map), manifest)
+}
+
+// A Secret holding the SecureString derived keys, followed by a ConfigMap holding the rest
+pub fn as_k8s_split_format(
+  map: HashMap<String, String>,
+  secure_keys: &HashSet<String>,
+  manifest: &ManifestConfig,
+) -> Result<String, Error> {
+  let (secrets, configs): (HashMap<String, String>, HashMap<String, String>) = map
+    .into_iter()
+    .partition(|(key, _)| secure_keys.contains(key));
+  let secret = as_k8s_secret_format(secrets, manifest)?;
+  let configmap = as_k8s_configmap_format(configs, manifest)?;
+  Ok(format!("{secret}---\n{configmap}"))
+}
+
+lazy_static! {
+  static ref K8S_KEY_RE: Regex = Regex::new(r"^[-._a-zA-Z0-9]+$").unwrap();
+}
+
+// Strings are written as JSON strings, which are valid double quoted YAML scalars
+fn as_k8s_manifest(
+  kind: &str,
+  secret_type: Option<&str>,
+  data: Vec<(String, String)>,
+  manifest: &ManifestConfig,
+) -> Result<String, Error> {
+  let quote = |val: &str| serde_json::to_string(val);
+  let mut lines = vec![
+    String::from("apiVersion: v1"),
+    format!("kind: {kind}"),
+    String::from("metadata:"),
+    format!(
+      "  name: {}",
+      quote(manifest.name.as_deref().unwrap_or("provide"))?
+    ),
+  ];
+  if let Some(namespace) = &manifest.namespace {
+    lines.push(format!("  namespace: {}", quote(namespace)?));
+  }
+  if !manifest.labels.is_empty() {
+    lines.push(String::from("  labels:"));
+    for (key, val) in manifest.labels.iter() {
+      lines.push(format!("    {}: {}", quote(key)?, quote(val)?));
+    }
+  }
+  if let Some(secret_type) = secret_type {
+    lines.push(format!("type: {secret_type}"));
+  }
+  if data.is_empty() {
+    lines.push(String::from("data: {}"));
+  } else {
+    lines.push(String::from("data:"));
+  }
+  for (key, val) in data {
+    if !K8S_KEY_RE.is_match(&key) {
+      return Err(Error::BadFormat(format!(
+        "Key {key:?} is not valid in a Kubernetes {kind}"
+      )));
+    }
+    lines.push(format!("  {}: {}", quote(&key)?, quote(&val)?));
+  }
+  Ok(lines.into_iter().map(|line| line + "\n").collect())
+}
+
+lazy_static! {
+  static ref TEMPLATE_RE: Regex =
+    Regex::new(r"\{\{\s*([^{}|\s]+)\s*((?:\|\s*[^{}|\s]+\s*)*)\}\}").unwrap();
+}
+
+/*
+    Replaces each {{ KEY }} in `template` with the value of KEY, passed through
+    any filters in order, e.g. {{ KEY | base64 | json }}.
+
+    Undefined keys are replaced with nothing, or fail rendering when `strict`.
+*/
+pub fn render_template(
+  template: &str,
+  vars: &HashMap<String, String>,
+  strict: bool,
+) -> Result<String, Error> {
+  let mut rendered = String::with_capacity(template.len());
+  let mut undefined = BTreeSet::<&str>::new();
+  let mut last = 0;
+  for captures in TEMPLATE_RE.captures_iter(template) {
+    let whole = captures.get(0).unwrap();
+    let key = captures.get(1).unwrap().as_str();
+    let filters = captures.get(2).map(|m| m.as_str()).unwrap_or_default();
+    rendered.push_str(&template[last..whole.start()]);
+    last = whole.end();
+    let mut val = match vars.get(key) {
+      Some(val) => val.to_owned(),
+      None => {
+        undefined.insert(key);
+        String::new()
+      }
+    };
+    for filter in filters.split('|').map(str::trim).filter(|f| !f.is_empty()) {
+      val = apply_filter(filter, &val)?;
+    }
+    rendered.push_str(&val);
+  }
+  rendered.push_str(&template[last..]);
+  if strict && !undefined.is_empty() {
+    let keys: Vec<&str> = undefined.into_iter().collect();
+    return Err(Error::Error(format!(
+      "Undefined variables in template: {}",
+      keys.join(", ")
+    )));
+  }
+  Ok(rendered)
+}
+
+fn apply_filter(filter: &str, val: &str) -> Result<String, Error> {
+  match filter {
+    "base64" => Ok(base64::encode(val)),
+    "json" => Ok(serde_json::to_string(val)?),
+    "url" => Ok(escape_for_url(val)),
+    _ => Err(Error::BadFormat(format!(
+      "Unknown template filter {filter}"
+    ))),
+  }
+}
+
+// Percent-encodes everything but RFC 3986 unreserved characters
+pub fn escape_for_url(val: &str) -> String {
+  val
+    .bytes()
+    .map(|b| match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+        (b as char).to_string()
+      }
+      _ => format!("%{b:02X}"),
+    })
+    .collect()
+}
+
+// Orders pairs by key so formatted output is stable
+fn sorted(map: HashMap<String, String>) -> Vec<(String, String)> {
+  let mut pairs: Vec<(String, String)> = map.into_iter().collect();
+  pairs.sort();
+  pairs
+}
+
+lazy_static! {
+  static ref RE: Regex = Regex::new(r#"([$`"!\)\\])"#).unwrap();
+}
+
+// Escape $`"!)\ for use in bash
+pub fn escape_for_bash(val: &str) -> String {
+  RE.replace_all(val, "\\$1").into_owned()
+}
+
+pub fn merge_with_given(
+  lines: &[String],
+  use_base64: bool,
+) -> Result<HashMap<String, String>, Error> {
+  let map = lines
+    .iter()
+    .map(|line| parse_line(line, use_base64))
+    .collect::<Result<Vec<Option<Pair>>, Error>>()?
+    .into_iter()
+    .flatten()
+    .collect();
+  Ok(map)
+}
+
+pub fn merge_with_env(line: &str, use_base64: bool) -> Result<(String, String), Error> {
+  let key = line.to_string();
+  let maybe_encoded_val = env::var(&key)?;
+  let val = if use_base64 {
+    String::from_utf8(base64::decode(&maybe_encoded_val)?)?
+  } else {
+    maybe_encoded_val
+  };
+  Ok((key, val))
+}
+
+// Same as merge_with_commands_config with the default merge config
+pub fn merge_with_commands(
+  paths: &[String],
+  vars: &HashMap<String, String>,
+) -> Result<Vec<HashMap<String, String>>, Error> {
+  merge_with_commands_config(paths, vars, &MergeConfig::default())
+}
+
+pub fn merge_with_commands_config(
+  paths: &[String],
+  vars: &HashMap<String, String>,
+  merge_config: &MergeConfig,
+) -> Result<Vec<HashMap<String, String>>, Error> {
+  paths
+    .iter()
+    .map(|path| merge_with_command_config(path, vars, merge_config))
+    .collect()
+}
+
+// Same as merge_with_command_config with the default merge config
+pub fn merge_with_command(
+  command_line: &String,
+  vars: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, Error> {
+  merge_with_command_config(command_line, vars, &MergeConfig::default())
+}
+
+/*
+    Runs a merge command, given as a program with arguments quoted as in a
+    shell. Programs given as a path, such as ./merge.sh, are run as they are
+    and other programs are looked up in PATH. Its stderr is passed through and
+    its output read as the merge format says. The command is stopped when it
+    runs past the merge timeout, failing with Error::TimeoutError.
+*/
+pub fn merge_with_command_config(
+  command_line: &String,
+  vars: &HashMap<String, String>,
+  merge_config: &MergeConfig,
+) -> Result<HashMap<String, String>, Error> {
+  let words = split_command(command_line)?;
+  let (program, args) = words
+    .split_first()
+    .ok_or_else(|| Error::MergeError(String::from("Empty merge command")))?;
+  // As in a shell, a file in the working directory is only run when given as a path
+  let program = match program.contains('/') || program.contains(MAIN_SEPARATOR) {
+    true => fs::canonicalize(program)?,
+    false => PathBuf::from(program),
+  };
+  let mut command = Command::new(program);
+  command.args(args).stderr(Stdio::inherit());
+  let input = match merge_config.input {
+    MergeInput::ENV => {
+      command.envs(vars);
+      None
+    }
+    MergeInput::JSON => Some(serde_json::to_vec(
+      &vars.iter().collect::<BTreeMap<_, _>>(),
+    )?),
+  };
+  let grace_period = merge_config.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD);
+  // Only None when there is a timeout
+  let output =
+    output_within(command, input, merge_config.timeout, grace_period)?.ok_or_else(|| {
+      Error::TimeoutError(
+        command_line.to_owned(),
+        merge_config.timeout.unwrap_or_default(),
+      )
+    })?;
+  match output.status.code() {
+    Some(0) => read_merge_output(&output.stdout, merge_config.format),
+    Some(code) => Err(Error::MergeError(format!(
+      "{command_line} exited with code {code}"
+    ))),
+    None => Err(Error::MergeError(format!(
+      "{command_line} was terminated by signal"
+    ))),
+  }
+}
+
+pub fn read_merge_output(
+  output: &[u8],
+  format: MergeFormat,
+) -> Result<HashMap<String, String>, Error> {
+  match format {
+    MergeFormat::RAW => read_from_reader(BufReader::new(Cursor::new(output)), false),
+    MergeFormat::BASE64 => read_from_reader(BufReader::new(Cursor::new(output)), true),
+    MergeFormat::JSON => serde_json::from_slice::<HashMap<String, serde_json::Value>>(output)?
+      .into_iter()
+      .map(|(key, val)| match val {
+        serde_json::Value::String(val) => Ok((key, val)),
+        serde_json::Value::Number(_) | serde_json::Value::Bool(_) => Ok((key, val.to_string())),
+        _ => Err(Error::MergeError(format!(
+          "Value of {key} is not a string, number or boolean"
+        ))),
+      })
+      .collect(),
+  }
+}
+
+/*
+    Splits a command line into words the way a POSIX shell does, without any
+    expansion: single quotes keep everything literally, double quotes and
+    backslashes escape the next character.
+*/
+pub fn split_command(command_line: &str) -> Result<Vec<String>, Error> {
+  let mut words = vec![];
+  let mut word: Option<String> = None;
+  let mut chars = command_line.chars();
+  let unterminated = || Error::MergeError(format!("Unterminated quote in {command_line}"));
+  while let Some(c) = chars.next() {
+    match c {
+      c if c.is_whitespace() => words.extend(word.take()),
+      '\'' => {
+        let quoted = word.get_or_insert_with(String::new);
+        loop {
+          match chars.next().ok_or_else(unterminated)? {
+            '\'' => break,
+            c => quoted.push(c),
+          }
+        }
+      }
+      '"' => {
+        let quoted = word.get_or_insert_with(String::new);
+        loop {
+          match chars.next().ok_or_else(unterminated)? {
+            '"' => break,
+            '\\' => match chars.next().ok_or_else(unterminated)? {
+              c @ ('"' | '\\' | '$' | '`') => quoted.push(c),
+              '\n' => {}
+              c => {
+                quoted.push('\\');
+                quoted.push(c);
+              }
+            },
+            c => quoted.push(c),
+          }
+        }
+      }
+      '\\' => {
+        if let Some(c) = chars.next() {
+          word.get_or_insert_with(String::new).push(c);
+        }
+      }
+      c => word.get_or_insert_with(String::new).push(c),
+    }
+  }
+  words.extend(word);
+  Ok(words)
+}
+
+/*
+    Same as Command::output, writing `input` to stdin and leaving stderr as
+    the command set it. Stops the command and returns None when it runs past
+    `timeout`.
+*/
+fn output_within(
+  mut command: Command,
+  input: Option<Vec<u8>>,
+  timeout: Option<Duration>,
+  grace_period: Duration,
+) -> Result<Option<Output>, Error> {
+  let deadline = timeout.map(|timeout| Instant::now() + timeout);
+  let stdin = match input {
+    Some(_) => Stdio::piped(),
+    None => Stdio::null(),
+  };
+  let group = own_process_group(&mut command);
+  let mut child = command.stdin(stdin).stdout(Stdio::piped()).spawn()?;
+  // Written and read while the command runs so that it never blocks on a full pipe
+  let writer = match (child.stdin.take(), input) {
+    (Some(mut stdin), Some(input)) => Some(thread::spawn(move || {
+      match stdin.write_all(&input) {
+        // Commands are free not to read their input
+        Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+      }
+    })),
+    _ => None,
+  };
+  let reader = child.stdout.take().map(|mut stdout| {
+    thread::spawn(move || {
+      let mut bytes = vec![];
+      stdout.read_to_end(&mut bytes).map(|_| bytes)
+    })
+  });
+  let status = match deadline {
+    Some(deadline) => match wait_until(&mut child, deadline)? {
+      Some(status) => status,
+      None => {
+        // The threads are left behind as processes it started may keep the pipes open
+        stop_child(&mut child, grace_period, group)?;
+        return Ok(None);
+      }
+    },
+    None => child.wait()?,
+  };
+  if let Some(writer) = writer {
+    writer.join().expect("input thread panicked")?;
+  }
+  let stdout = match reader {
+    Some(reader) => reader.join().expect("output thread panicked")?,
+    None => vec![],
+  };
+  Ok(Some(Output {
+    status,
+    stdout,
+    stderr: vec![],
+  }))
+}
+
+// Waits for the child to exit until `deadline`, returning None if it is still running then
+fn wait_until(child: &mut Child, deadline: Instant) -> Result<Option<ExitStatus>, Error> {
+  loop {
+    if let Some(status) = child.try_wait()? {
+      return Ok(Some(status));
+    }
+    if Instant::now() >= deadline {
+      return Ok(None);
+    }
+    thread::sleep(CHILD_POLL_INTERVAL);
+  }
+}
+
+/*
+    Starts the command in its own process group, so that stopping it stops
+    what it started too, and returns whether it did. Not when provide reads a
+    terminal: the command stays in its foreground group to be able to read it
+    and to get Ctrl-C, and only the command itself is stopped.
+*/
+#[cfg(unix)]
+fn own_process_group(command: &mut Command) -> bool {
+  if std::io::stdin().is_terminal() {
+    return false;
+  }
+  command.process_group(0);
+  true
+}
+
+#[cfg(not(unix))]
+fn own_process_group(_command: &mut Command) -> bool {
+  false
+}
+
+// Sends SIGTERM to the child or its process group, or kills it where there are no signals
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn terminate(child: &mut Child, group: bool) -> Result<(), Error> {
+  #[cfg(unix)]
+  return signal_child(child, Signal::SIGTERM, group);
+  #[cfg(not(unix))]
+  return Ok(child.kill()?);
+}
+
+// Kills the child or its process group, which has not been waited for yet
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn kill_child(child: &mut Child, group: bool) -> Result<(), Error> {
+  #[cfg(unix)]
+  return signal_child(child, Signal::SIGKILL, group);
+  #[cfg(not(unix))]
+  return Ok(child.kill()?);
+}
+
+#[cfg(unix)]
+fn signal_child(child: &Child, signal: Signal, group: bool) -> Result<(), Error> {
+  let pid = child.id() as i32;
+  kill(Pid::from_raw(if group { -pid } else { pid }), signal)
+    .map_err(|errno| Error::Error(format!("Could not send {signal}: {errno}")))
+}
+
+// Same as Running::stop, blocking the current thread
+fn stop_child(child: &mut Child, grace_period: Duration, group: bool) -> Result<ExitStatus, Error> {
+  if let Some(status) = child.try_wait()? {
+    return Ok(status);
+  }
+  terminate(child, group)?;
+  match wait_until(child, Instant::now() + grace_period)? {
+    Some(status) => Ok(status),
+    None => {
+      kill_child(child, group)?;
+      Ok(child.wait()?)
+    }
+  }
+}
+
+pub fn run(run_config: RunConfig, vars: HashMap<String, String>) -> Result<(), Error> {
+  run_with_secure_keys(run_config, vars, &HashSet::new())
+}
+
+// Same as run, also able to redact values read from SecureStrings
+pub fn run_processed(run_config: RunConfig, processed: ProcessedParameters) -> Result<(), Error> {
+  run_with_secure_keys(
+    run_config,
+    expose_vars(processed.vars),
+    &processed.secure_keys,
+  )
+}
+
+fn run_with_secure_keys(
+  run_config: RunConfig,
+  vars: HashMap<String, String>,
+  secure_keys: &HashSet<String>,
+) -> Result<(), Error> {
+  let mut running = Running::start(&run_config, &vars, secure_keys)?;
+  let status = match running.deadline {
+    Some(deadline) => match wait_until(&mut running.child, deadline)? {
+      Some(status) => status,
+      None => {
+        running.timed_out = true;
+        stop_child(&mut running.child, running.grace_period, running.group)?
+      }
+    },
+    None => running.child.wait()?,
+  };
+  running.result(status)
+}
+
+// Same as run, also stopping the command on SIGTERM or SIGINT so that its secret files are removed
+pub async fn run_async(run_config: RunConfig, vars: HashMap<String, String>) -> Result<(), Error> {
+  run_async_with_secure_keys(run_config, vars, &HashSet::new()).await
+}
+
+// Same as run_processed, also stopping the command on SIGTERM or SIGINT
+pub async fn run_processed_async(
+  run_config: RunConfig,
+  processed: ProcessedParameters,
+) -> Result<(), Error> {
+  run_async_with_secure_keys(
+    run_config,
+    expose_vars(processed.vars),
+    &processed.secure_keys,
+  )
+  .await
+}
+
+async fn run_async_with_secure_keys(
+  run_config: RunConfig,
+  vars: HashMap<String, String>,
+  secure_keys: &HashSet<String>,
+) -> Result<(), Error> {
+  let mut shutdown = Shutdown::listen()?;
+  let mut running = Running::start(&run_config, &vars, secure_keys)?;
+  let status = tokio::select! {
+    status = running.wait() => status?,
+    _ = shutdown.recv() => running.stop().await?,
+  };
+  running.result(status)
+}
+
+fn exit_result(status: ExitStatus) -> Result<(), Error> {
+  match status.code() {
+    Some(0) => Ok(()),
+    Some(code) => Err(Error::Error(format!("Exit code {code}"))),
+    None => Err(Error::Error(String::from("Terminated by signal"))),
+  }
+}
+
+// How often a running command is checked for having exited
+const CHILD_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Time a command is given to exit after SIGTERM before it is killed
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+// A started command, with the threads redacting its output and its secret files
+struct Running {
+  child: Child,
+  copiers: Vec<thread::JoinHandle<std::io::Result<()>>>,
+  secret_dir: Option<PathBuf>,
+  secret_files: Option<SecretFilesConfig>,
+  redact: Option<RedactConfig>,
+  // Every value redacted so far, the command may still print old ones after a refresh
+  redacted: Vec<String>,
+  redactor: Arc<RwLock<Option<BytesRegex>>>,
+  // Names the command in errors
+  name: String,
+  timeout: Option<Duration>,
+  deadline: Option<Instant>,
+  grace_period: Duration,
+  // Whether the command was stopped for running past its timeout
+  timed_out: bool,
+  // Whether the command was killed for not exiting within the grace period
+  killed: bool,
+  started: Instant,
+  // Whether the command runs in its own process group, stopped as a whole
+  group: bool,
+}
+
+impl Running {
+  fn start(
+    run_config: &RunConfig,
+    vars: &HashMap<String, String>,
+    secure_keys: &HashSet<String>,
+  ) -> Result<Running, Error> {
+    let redacted = match &run_config.redact {
+      Some(redact) => redacted_values(redact, vars, secure_keys)?,
+      None => vec![],
+    };
+    let redactor = Arc::new(RwLock::new(values_redactor(&redacted)?));
+    let (vars, secret_dir) = match &run_config.secret_files {
+      Some(secret_files) => {
+        let (vars, dir) = write_secret_files(secret_files, vars.clone())?;
+        (vars, Some(dir))
+      }
+      None => (vars.clone(), None),
+    };
+    let mut command = Command::new(&run_config.cmd);
+    command.envs(vars);
+    command.args(&run_config.args);
+    let prefix = run_config.name.as_ref().map(|name| format!("{name} | "));
+    // Piped whenever redacting, values to redact may only show up after a refresh
+    let output = match (&run_config.redact, &prefix) {
+      (None, None) => Stdio::inherit,
+      _ => Stdio::piped,
+    };
+    command.stdout(output());
+    command.stderr(output());
+    let group = own_process_group(&mut command);
+    let mut child = match command.spawn() {
+      Ok(child) => child,
+      Err(err) => {
+        if let Some(dir) = &secret_dir {
+          let _ = fs::remove_dir_all(dir);
+        }
+        return Err(Error::IOError(err));
+      }
+    };
+    let mut copiers = vec![];
+    if let (Some(stdout), Some(stderr)) = (child.stdout.take(), child.stderr.take()) {
+      let prefix = prefix.unwrap_or_default();
+      let (stdout_redactor, stdout_prefix) = (redactor.clone(), prefix.clone());
+      copiers.push(thread::spawn(move || {
+        copy_shared_lines(stdout, std::io::stdout(), &stdout_redactor, &stdout_prefix)
+      }));
+      let stderr_redactor = redactor.clone();
+      copiers.push(thread::spawn(move || {
+        copy_shared_lines(stderr, std::io::stderr(), &stderr_redactor, &prefix)
+      }));
+    }
+    Ok(Running {
+      child,
+      copiers,
+      secret_dir,
+      secret_files: run_config.secret_files.clone(),
+      redact: run_config.redact.clone(),
+      redacted,
+      redactor,
+      name: command_name(run_config).to_owned(),
+      timeout: run_config.timeout,
+      deadline: run_config.timeout.map(|timeout| Instant::now() + timeout),
+      grace_period: run_config.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD),
+      timed_out: false,
+      killed: false,
+      started: Instant::now(),
+      group,
+    })
+  }
+
+  fn try_wait(&mut self) -> Result<Option<ExitStatus>, Error> {
+    Ok(self.child.try_wait()?)
+  }
+
+  async fn wait(&mut self) -> Result<ExitStatus, Error> {
+    loop {
+      if let Some(status) = self.try_wait()? {
+        return Ok(status);
+      }
+      if let Some(status) = self.stop_if_timed_out().await? {
+        return Ok(status);
+      }
+      tokio::time::sleep(CHILD_POLL_INTERVAL).await;
+    }
+  }
+
+  // Stops the command once it runs past its timeout, returning how it exited then
+  async fn stop_if_timed_out(&mut self) -> Result<Option<ExitStatus>, Error> {
+    match self.deadline {
+      Some(deadline) if Instant::now() >= deadline => {
+        self.timed_out = true;
+        Ok(Some(self.stop().await?))
+      }
+      _ => Ok(None),
+    }
+  }
+
+  // Redacts and rewrites the secret files of the new values, without restarting the command
+  fn refresh(
+    &mut self,
+    vars: &HashMap<String, String>,
+    secure_keys: &HashSet<String>,
+  ) -> Result<(), Error> {
+    if let Some(redact) = &self.redact {
+      self
+        .redacted
+        .extend(redacted_values(redact, vars, secure_keys)?);
+      self.redacted.sort();
+      self.redacted.dedup();
+      let redactor = values_redactor(&self.redacted)?;
+      *self.redactor.write().unwrap_or_else(|err| err.into_inner()) = redactor;
+    }
+    if let (Some(secret_files), Some(dir)) = (&self.secret_files, &self.secret_dir) {
+      rewrite_secret_files(dir, secret_files, vars.clone())?;
+    }
+    Ok(())
+  }
+
+  #[cfg(unix)]
+  fn signal(&self, signal: Signal) -> Result<(), Error> {
+    kill(Pid::from_raw(self.child.id() as i32), signal)
+      .map_err(|errno| Error::Error(format!("Could not send {signal}: {errno}")))
+  }
+
+  // Asks the command to exit with SIGTERM, killing it if it is still running after the grace period
+  async fn stop(&mut self) -> Result<ExitStatus, Error> {
+    if let Some(status) = self.try_wait()? {
+      return Ok(status);
+    }
+    terminate(&mut self.child, self.group)?;
+    let deadline = Instant::now() + self.grace_period;
+    while Instant::now() < deadline {
+      if let Some(status) = self.try_wait()? {
+        return Ok(status);
+      }
+      tokio::time::sleep(CHILD_POLL_INTERVAL).await;
+    }
+    kill_child(&mut self.child, self.group)?;
+    self.killed = true;
+    Ok(self.child.wait()?)
+  }
+
+  // Same as finish, failing when the command timed out, had to be killed or exited with an error
+  fn result(self, status: ExitStatus) -> Result<(), Error> {
+    let timeout_error = match (self.timed_out, self.timeout) {
+      (true, Some(timeout)) => Some(Error::TimeoutError(self.name.clone(), timeout)),
+      _ if self.killed => Some(Error::KilledError(self.name.clone(), self.grace_period)),
+      _ => None,
+    };
+    let status = self.finish(status)?;
+    match timeout_error {
+      Some(err) => Err(err),
+      None => exit_result(status),
+    }
+  }
+
+  // Waits for the output to be copied and removes secret files once the command exited
+  fn finish(self, status: ExitStatus) -> Result<ExitStatus, Error> {
+    // Removed first so that failing to copy output never leaves secrets behind
+    let removed = match self.secret_dir {
+      Some(dir) => fs::remove_dir_all(dir),
+      None => Ok(()),
+    };
+    let mut copied = Ok(());
+    for copier in self.copiers {
+      let result = copier.join().expect("output thread panicked");
+      if copied.is_ok() {
+        copied = result;
+      }
+    }
+    removed?;
+    copied?;
+    Ok(status)
+  }
+}
+
+#[cfg(unix)]
+pub fn parse_signal(name: &str) -> Result<Signal, Error> {
+  let name = name.to_uppercase();
+  let name = match name.starts_with("SIG") {
+    true => name,
+    false => format!("SIG{name}"),
+  };
+  name
+    .parse()
+    .map_err(|_| Error::BadFormat(format!("Unknown signal {name}")))
+}
+
+#[cfg(not(unix))]
+pub fn parse_signal(name: &str) -> Result<(), Error> {
+  Err(Error::BadFormat(format!(
+    "Signal {name} is not supported on this platform"
+  )))
+}
+
+// 500ms, 30s, 5m, 1h or a number of seconds
+pub fn parse_duration(val: &str) -> Result<Duration, Error> {
+  let invalid = || Error::BadFormat(format!("Invalid duration {val}"));
+  let split = val.find(|c: char| !c.is_ascii_digit()).unwrap_or(val.len());
+  let (number, unit) = val.split_at(split);
+  let number: u64 = number.parse().map_err(|_| invalid())?;
+  match unit {
+    "ms" => Ok(Duration::from_millis(number)),
+    "s" | "" => Ok(Duration::from_secs(number)),
+    "m" => Ok(Duration::from_secs(number * 60)),
+    "h" => Ok(Duration::from_secs(number * 60 * 60)),
+    _ => Err(invalid()),
+  }
+}
+
+/*
+    Runs the command while resolving its variables again every interval. When
+    they change, they are written to the --output file, if any, and the
+    command is sent the watch signal, or restarted when there is none.
+    Returns once the command exits on its own.
+*/
+pub async fn watch(options: ProcessParametersOptions, watch: WatchConfig) -> Result<(), Error> {
+  let config = SupervisorConfig {
+    watch: Some(watch),
+    ..SupervisorConfig::default()
+  };
+  supervise(options, config).await
+}
+
+/*
+    Runs the command, watching its variables when asked to as watch does, and
+    starting it again after it exits as the restart policy asks, with variables
+    resolved again. On SIGTERM or SIGINT the command is stopped and this
+    returns without restarting it.
+*/
+pub async fn supervise(
+  options: ProcessParametersOptions,
+  config: SupervisorConfig,
+) -> Result<(), Error> {
+  let run_config = options
+    .run_config
+    .clone()
+    .ok_or_else(|| Error::Error(String::from("Nothing to run")))?;
+  let watch = config.watch.as_ref();
+  #[cfg(unix)]
+  let signal = watch
+    .and_then(|watch| watch.signal.as_deref())
+    .map(parse_signal)
+    .transpose()?;
+  #[cfg(not(unix))]
+  if let Some(name) = watch.and_then(|watch| watch.signal.as_deref()) {
+    parse_signal(name)?;
+  }
+  let mut shutdown = Shutdown::listen()?;
+  let mut processed = process_parameters_detailed(options.clone()).await?;
+  export_vars(&options.format_config, &processed)?;
+  let mut running = start_processed(&run_config, &processed)?;
+  let mut stamps = include_stamps(&options);
+  let mut next_poll = watch.map(|watch| Instant::now() + watch.interval);
+  let mut restarts = 0;
+  loop {
+    let exited = match running.try_wait()? {
+      Some(status) => Some(status),
+      None => running.stop_if_timed_out().await?,
+    };
+    if let Some(status) = exited {
+      if running.timed_out {
+        eprintln!(
+          "Command did not exit within {:?}, stopped it",
+          running.timeout.unwrap_or_default()
+        );
+      }
+      // A command that ran for as long as the longest delay recovered, its next failure starts over
+      if running.started.elapsed() >= config.restart.max_delay {
+        restarts = 0;
+      }
+      if !should_restart(&config.restart, status, restarts) {
+        return running.result(status);
+      }
+      let status = running.finish(status)?;
+      restarts += 1;
+      let delay = restart_delay(&config.restart, restarts);
+      eprintln!("Command exited with {status}, restarting in {delay:?}");
+      tokio::select! {
+        _ = tokio::time::sleep(delay) => {}
+        _ = shutdown.recv() => return exit_result(status),
+      }
+      if let Some(new) = resolve_again(&options).await {
+        processed = new;
+        export_vars(&options.format_config, &processed)?;
+      }
+      running = start_processed(&run_config, &processed)?;
+      continue;
+    }
+    if let (Some(watch), Some(poll)) = (watch, next_poll) {
+      if Instant::now() >= poll {
+        next_poll = Some(Instant::now() + watch.interval);
+        let new_stamps = include_stamps(&options);
+        if polls_remote(&options) || new_stamps != stamps {
+          stamps = new_stamps;
+          match resolve_again(&options).await {
+            Some(new) if new.vars != processed.vars => {
+              processed = new;
+              export_vars(&options.format_config, &processed)?;
+              #[cfg(unix)]
+              if let Some(signal) = signal {
+                running.refresh(&exposed_vars(&processed), &processed.secure_keys)?;
+                running.signal(signal)?;
+                continue;
+              }
+              let status = running.stop().await?;
+              running.finish(status)?;
+              running = start_processed(&run_config, &processed)?;
+            }
+            _ => {}
+          }
+        }
+      }
+    }
+    tokio::select! {
+      _ = tokio::time::sleep(CHILD_POLL_INTERVAL) => {}
+      _ = shutdown.recv() => {
+        let status = running.stop().await?;
+        return running.result(status);
+      }
+    }
+  }
+}
+
+fn should_restart(restart: &RestartConfig, status: ExitStatus, restarts: u32) -> bool {
+  let wanted = match restart.policy {
+    RestartPolicy::NEVER => false,
+    RestartPolicy::ON_FAILURE => !status.success(),
+    RestartPolicy::ALWAYS => true,
+  };
+  match restart.max_restarts {
+    Some(max) if wanted && restarts >= max => {
+      eprintln!("Command exited with {status}, not restarting after {max} restarts");
+      false
+    }
+    _ => wanted,
+  }
+}
+
+// The delay before the nth restart, doubling every time up to the max delay
+pub fn restart_delay(restart: &RestartConfig, restarts: u32) -> Duration {
+  let factor = 2u32.saturating_pow(restarts.saturating_sub(1));
+  restart.delay.saturating_mul(factor).min(restart.max_delay)
+}
+
+// Keeps the command running with what it has until the sources recover
+async fn resolve_again(options: &ProcessParametersOptions) -> Option<ProcessedParameters> {
+  match process_parameters_detailed(options.clone()).await {
+    Ok(processed) => Some(processed),
+    Err(err) => {
+      eprintln!("Could not resolve variables, keeping the current ones: {err}");
+      None
+    }
+  }
+}
+
+// Completes on the first SIGTERM or SIGINT received by provide
+struct Shutdown {
+  #[cfg(unix)]
+  terminate: tokio::signal::unix::Signal,
+  #[cfg(unix)]
+  interrupt: tokio::signal::unix::Signal,
+}
+
+impl Shutdown {
+  fn listen() -> Result<Shutdown, Error> {
+    #[cfg(unix)]
+    {
+      use tokio::signal::unix::{signal, SignalKind};
+      Ok(Shutdown {
+        terminate: signal(SignalKind::terminate())?,
+        interrupt: signal(SignalKind::interrupt())?,
+      })
+    }
+    #[cfg(not(unix))]
+    Ok(Shutdown {})
+  }
+
+  async fn recv(&mut self) {
+    #[cfg(unix)]
+    tokio::select! {
+      _ = self.terminate.recv() => {}
+      _ = self.interrupt.recv() => {}
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+  }
+}
+
+/*
+    Runs every command with the same variables, one after the other or all at
+    once. Unless `keep_going`, the first failure stops the rest: commands not
+    started yet are skipped and running ones are stopped. On SIGTERM or SIGINT
+    every running command is stopped.
+*/
+pub async fn run_all(
+  process_list: &ProcessList,
+  processed: &ProcessedParameters,
+) -> Result<(), Error> {
+  // Names are padded so that output lines up
+  let width = process_list
+    .commands
+    .iter()
+    .filter_map(|command| command.name.as_ref())
+    .map(|name| name.chars().count())
+    .max()
+    .unwrap_or_default();
+  let commands: Vec<RunConfig> = process_list
+    .commands
+    .iter()
+    .map(|command| RunConfig {
+      name: command.name.as_ref().map(|name| format!("{name:<width$}")),
+      ..command.clone()
+    })
+    .collect();
+  let mut shutdown = Shutdown::listen()?;
+  let mut failures = vec![];
+  match process_list.mode {
+    RunMode::SEQUENTIAL => {
+      for command in &commands {
+        let mut running = start_processed(command, processed)?;
+        let mut shut_down = false;
+        let status = tokio::select! {
+          status = running.wait() => status?,
+          _ = shutdown.recv() => {
+            shut_down = true;
+            running.stop().await?
+          }
+        };
+        if let Err(err) = running.result(status) {
+          failures.push(format!("{}: {err}", command_name(command)));
+          if !process_list.keep_going {
+            break;
+          }
+        }
+        // The rest is not started, even with --keep-going
+        if shut_down {
+          break;
+        }
+      }
+    }
+    RunMode::CONCURRENT => {
+      let mut running = vec![];
+      for command in &commands {
+        match start_processed(command, processed) {
+          Ok(started) => running.push((command, started)),
+          Err(err) => {
+            stop_all(running).await?;
+            return Err(err);
+          }
+        }
+      }
+      while !running.is_empty() {
+        let mut index = 0;
+        while index < running.len() {
+          let exited = match running[index].1.try_wait()? {
+            Some(status) => Some(status),
+            None => running[index].1.stop_if_timed_out().await?,
+          };
+          match exited {
+            Some(status) => {
+              let (command, exited) = running.remove(index);
+              if let Err(err) = exited.result(status) {
+                failures.push(format!("{}: {err}", command_name(command)));
+              }
+            }
+            None => index += 1,
+          }
+        }
+        if !failures.is_empty() && !process_list.keep_going {
+          // Commands stopped because another one failed did not fail themselves
+          stop_all(running).await?;
+          break;
+        }
+        tokio::select! {
+          _ = tokio::time::sleep(CHILD_POLL_INTERVAL) => {}
+          _ = shutdown.recv() => {
+            failures.extend(stop_all(running).await?);
+            break;
+          }
+        }
+      }
+    }
+  }
+  match failures.is_empty() {
+    true => Ok(()),
+    false => Err(Error::Error(failures.join(", "))),
+  }
+}
+
+fn command_name(command: &RunConfig) -> &str {
+  command.name.as_deref().unwrap_or(&command.cmd).trim_end()
+}
+
+// Stops the commands, returning how those that did not exit cleanly failed
+async fn stop_all(running: Vec<(&RunConfig, Running)>) -> Result<Vec<String>, Error> {
+  let mut failures = vec![];
+  for (command, mut running) in running {
+    let status = running.stop().await?;
+    if let Err(err) = running.result(status) {
+      failures.push(format!("{}: {err}", command_name(command)));
+    }
+  }
+  Ok(failures)
+}
+
+// Reads lines of NAME: COMMAND, ignoring empty lines and lines starting with #
+pub fn read_procfile(path: &str) -> Result<Vec<(String, String)>, Error> {
+  let reader = with_file(fs::canonicalize(path)?)?;
+  let mut commands = vec![];
+  for line in reader.lines() {
+    let line = line?;
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    match line.split_once(':') {
+      Some((name, command)) if !name.trim().is_empty() && !command.trim().is_empty() => {
+        commands.push((name.trim().to_owned(), command.trim().to_owned()))
+      }
+      _ => {
+        return Err(Error::BadFormat(format!(
+          "Invalid process {line}, expected NAME: COMMAND"
+        )))
+      }
+    }
+  }
+  Ok(commands)
+}
+
+fn start_processed(
+  run_config: &RunConfig,
+  processed: &ProcessedParameters,
+) -> Result<Running, Error> {
+  Running::start(run_config, &exposed_vars(processed), &processed.secure_keys)
+}
+
+fn exposed_vars(processed: &ProcessedParameters) -> HashMap<String, String> {
+  processed
+    .vars
+    .iter()
+    .map(|(key, val)| (key.to_owned(), val.expose().to_owned()))
+    .collect()
+}
+
+// Writes the formatted variables to the --output file, for commands that reload it
+fn export_vars(format_config: &FormatConfig, processed: &ProcessedParameters) -> Result<(), Error> {
+  if let Some(output) = &format_config.output {
+    let formatted = format_vars(
+      format_config,
+      exposed_vars(processed),
+      &processed.secure_keys,
+    )?;
+    write_output(Path::new(&output.path), &formatted, output)?;
+  }
+  Ok(())
+}
+
+// Parameters and --merge commands can change at any time, unlike include files
+fn polls_remote(options: &ProcessParametersOptions) -> bool {
+  options.mode == Some(Mode::GET) || options.params.is_some() || options.merges.is_some()
+}
+
+fn include_stamps(options: &ProcessParametersOptions) -> Vec<Option<SystemTime>> {
+  options
+    .includes
+    .iter()
+    .flatten()
+    .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+    .collect()
+}
+
+// Shorter values would have *** inserted all over the output
+const MIN_REDACTED_LENGTH: usize = 4;
+
+/*
+    Matches the values to redact, longest first so that a value containing
+    another is replaced whole. As output is redacted line by line, every line
+    of a multi-line value is matched on its own. Values shorter than
+    MIN_REDACTED_LENGTH are not redacted, with a warning. None when there is
+    nothing to redact.
+*/
+pub fn redactor(
+  redact: &RedactConfig,
+  vars: &HashMap<String, String>,
+  secure_keys: &HashSet<String>,
+) -> Result<Option<BytesRegex>, Error> {
+  values_redactor(&redacted_values(redact, vars, secure_keys)?)
+}
+
+// Lines of the values to redact, with a warning for values too short to redact
+fn redacted_values(
+  redact: &RedactConfig,
+  vars: &HashMap<String, String>,
+  secure_keys: &HashSet<String>,
+) -> Result<Vec<String>, Error> {
+  let keys = redact
+    .keys
+    .iter()
+    .map(|pattern| Regex::new(pattern))
+    .collect::<Result<Vec<Regex>, _>>()?;
+  let mut values = Vec::<String>::new();
+  for (key, val) in vars {
+    let redacted =
+      (redact.secure && secure_keys.contains(key)) || keys.iter().any(|re| re.is_match(key));
+    if !redacted {
+      continue;
+    }
+    let val = val.trim();
+    if val.is_empty() {
+      continue;
+    }
+    if val.chars().count() < MIN_REDACTED_LENGTH {
+      eprintln!(
+        "Warning: not redacting {key}, its value is shorter than {MIN_REDACTED_LENGTH} characters"
+      );
+      continue;
+    }
+    // Short lines such as a closing brace are left alone, the rest of the value is redacted
+    values.extend(
+      val
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.chars().count() >= MIN_REDACTED_LENGTH)
+        .map(String::from),
+    );
+  }
+  Ok(values)
+}
+
+fn values_redactor(values: &[String]) -> Result<Option<BytesRegex>, Error> {
+  if values.is_empty() {
+    return Ok(None);
+  }
+  let mut values: Vec<&str> = values.iter().map(String::as_str).collect();
+  values.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+  values.dedup();
+  let escaped: Vec<String> = values.into_iter().map(regex::escape).collect();
+  Ok(Some(BytesRegex::new(&escaped.join("|"))?))
+}
+
+// Copies each line of `reader` to `writer` as soon as it is read, with matches replaced by ***
+pub fn redact_lines(
+  reader: impl Read,
+  writer: impl Write,
+  redactor: &BytesRegex,
+) -> Result<(), std::io::Error> {
+  copy_lines(reader, writer, Some(redactor), "")
+}
+
+// Same as redact_lines, redacting only when given a redactor and starting every line with `prefix`
+pub fn copy_lines(
+  reader: impl Read,
+  writer: impl Write,
+  redactor: Option<&BytesRegex>,
+  prefix: &str,
+) -> Result<(), std::io::Error> {
+  copy_lines_with(reader, writer, prefix, |line, output| {
+    redact_line(line, output, redactor)
+  })
+}
+
+// Same as copy_lines, with a redactor that can be replaced while copying
+fn copy_shared_lines(
+  reader: impl Read,
+  writer: impl Write,
+  redactor: &RwLock<Option<BytesRegex>>,
+  prefix: &str,
+) -> Result<(), std::io::Error> {
+  copy_lines_with(reader, writer, prefix, |line, output| {
+    let redactor = redactor.read().unwrap_or_else(|err| err.into_inner());
+    redact_line(line, output, redactor.as_ref())
+  })
+}
+
+fn copy_lines_with(
+  reader: impl Read,
+  mut writer: impl Write,
+  prefix: &str,
+  redact: impl Fn(&[u8], &mut Vec<u8>),
+) -> Result<(), std::io::Error> {
+  let mut reader = BufReader::new(reader);
+  let mut line = Vec::new();
+  loop {
+    line.clear();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+      return Ok(());
+    }
+    // Written at once so lines of commands sharing the writer are not mixed up
+    let mut output = prefix.as_bytes().to_vec();
+    redact(&line, &mut output);
+    writer.write_all(&output)?;
+    writer.flush()?;
+  }
+}
+
+fn redact_line(line: &[u8], output: &mut Vec<u8>, redactor: Option<&BytesRegex>) {
+  match redactor {
+    Some(redactor) => output.extend_from_slice(&redactor.replace_all(line, NoExpand(b"***"))),
+    None => output.extend_from_slice(line),
+  }
+}
+
+// Directory used for secret files, preferring memory backed /dev/shm over the disk
+fn secret_files_base_dir(secret_files: &SecretFilesConfig) -> PathBuf {
+  match &secret_files.dir {
+    Some(dir) => PathBuf::from(dir),
+    None if Path::new("/dev/shm").is_dir() => PathBuf::from("/dev/shm"),
+    None => env::temp_dir(),
+  }
+}
+
+/*
+    Writes the selected variables as files in a new directory only the current
+    user can read, removing them from the returned variables. The directory is
+    exposed as PROVIDE_SECRETS_DIR and, when asked for, each file as <KEY>_FILE.
+*/
+pub fn write_secret_files(
+  secret_files: &SecretFilesConfig,
+  vars: HashMap<String, String>,
+) -> Result<(HashMap<String, String>, PathBuf), Error> {
+  // Commands started by the same process each get their own directory
+  static STARTED: AtomicUsize = AtomicUsize::new(0);
+  let name = format!(
+    "provide-{}-{}",
+    std::process::id(),
+    STARTED.fetch_add(1, Ordering::SeqCst)
+  );
+  let dir = secret_files_base_dir(secret_files).join(name);
+  let mut builder = fs::DirBuilder::new();
+  #[cfg(unix)]
+  builder.mode(0o700);
+  builder.create(&dir)?;
+  match fill_secret_dir(&dir, secret_files, vars) {
+    Ok(vars) => Ok((vars, dir)),
+    Err(err) => {
+      let _ = fs::remove_dir_all(&dir);
+      Err(err)
+    }
+  }
+}
+
+/*
+    Replaces the files in a directory from write_secret_files with the new
+    values, each atomically so the command never reads a partial file. Files of
+    variables that are gone are removed.
+*/
+pub fn rewrite_secret_files(
+  dir: &Path,
+  secret_files: &SecretFilesConfig,
+  vars: HashMap<String, String>,
+) -> Result<(), Error> {
+  let keys: HashSet<String> = match &secret_files.keys {
+    Some(keys) => keys.iter().cloned().collect(),
+    None => vars.keys().cloned().collect(),
+  };
+  fill_secret_dir(dir, secret_files, vars)?;
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    if !keys.contains(&*entry.file_name().to_string_lossy()) {
+      fs::remove_file(entry.path())?;
+    }
+  }
+  Ok(())
+}
+
+fn fill_secret_dir(
+  dir: &Path,
+  secret_files: &SecretFilesConfig,
+  mut vars: HashMap<String, String>,
+) -> Result<HashMap<String, String>, Error> {
+  let keys: Vec<String> = match &secret_files.keys {
+    Some(keys) => keys.clone(),
+    None => vars.keys().cloned().collect(),
+  };
+  for key in keys {
+    let val = vars
+      .remove(&key)
+      .ok_or_else(|| Error::Error(format!("No variable {key} to write as a file")))?;
+    if key.contains(MAIN_SEPARATOR) || key == "." || key == ".." {
+      return Err(Error::BadFormat(format!(
+        "Key {key:?} is not a valid file name"
+      )));
+    }
+    let path = dir.join(&key);
+    write_output(&path, &val, &OutputConfig::default())?;
+    if secret_files.file_vars {
+      vars.insert(format!("{key}_FILE"), path.to_string_lossy().into_owned());
+    }
   }
+  vars.insert(
+    "PROVIDE_SECRETS_DIR".to_owned(),
+    dir.to_string_lossy().into_owned(),
+  );
+  Ok(vars)
 }
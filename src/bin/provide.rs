@@ -1,10 +1,15 @@
-use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches};
+#![allow(clippy::result_large_err)]
+
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
 use provide::api;
 use provide::types::*;
 use provide::Error;
 use std::collections::HashMap;
 use std::env;
-use tokio;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() {
@@ -16,20 +21,44 @@ async fn main() {
 }
 
 async fn run_app() -> Result<(), Error> {
-  let mut app = app();
+  let app = app();
   let matches = app.clone().get_matches();
+  match matches.subcommand() {
+    ("diff", Some(diff_matches)) => run_diff(diff_matches).await,
+    ("promote", Some(promote_matches)) => run_promote(promote_matches).await,
+    ("list", Some(list_matches)) => run_list(list_matches).await,
+    ("sync", Some(sync_matches)) => run_sync(sync_matches).await,
+    ("history", Some(history_matches)) => run_history(history_matches).await,
+    ("rollback", Some(rollback_matches)) => run_rollback(rollback_matches).await,
+    ("render", Some(render_matches)) => run_render(render_matches).await,
+    ("explain", Some(explain_matches)) => run_explain(explain_matches).await,
+    _ => run_provide(app, matches).await,
+  }
+}
+
+async fn run_provide(mut app: App<'_, '_>, matches: ArgMatches<'_>) -> Result<(), Error> {
   match matches.args.len() {
     // No args given means one match (region with default), so
     // we show help instead
     1 => Ok(app.print_help()?),
     _ => {
-      let options = options_from_matches(matches)?;
+      let options = options_from_matches(matches.clone())?;
+      if let Some(process_list) = process_list_from_matches(&matches)? {
+        let processed = api::process_parameters_detailed(options).await?;
+        report_processed(&matches, &processed);
+        return api::run_all(&process_list, &processed).await;
+      }
+      let supervisor_config = supervisor_config_from_matches(&matches)?;
+      if supervisor_config != SupervisorConfig::default() {
+        return api::supervise(options, supervisor_config).await;
+      }
       let format_config = options.format_config.clone();
       let maybe_run_config = options.run_config.clone();
-      let vars: HashMap<String, String> = api::process_parameters(options).await?;
+      let processed = api::process_parameters_detailed(options).await?;
+      report_processed(&matches, &processed);
       match maybe_run_config {
-        Some(run_config) => Ok(api::run(run_config, vars)?),
-        None => Ok(display(format_config, vars)),
+        Some(run_config) => api::run_processed_async(run_config, processed).await,
+        None => display(&format_config, processed),
       }
     }
   }
@@ -43,107 +72,215 @@ fn app<'a, 'b>() -> App<'a, 'b> {
         .settings(&[AppSettings::TrailingVarArg])
         .about("Provides environment variables from AWS Parameter Store")
 
-        .arg(Arg::with_name("get")
-            .long("get")
-            .takes_value(false)
-            .requires_all(&["application", "target"])
-            .help("Read AWS vars"))
+        .args(&source_args())
 
         .group(ArgGroup::with_name("mode")
             .args(&["get"])
             .required(false))
 
-        .arg(Arg::with_name("application")
+        .arg(Arg::with_name("format")
             .required(false)
-            .short("a")
-            .long("application")
+            .short("f")
+            .long("format")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .help("Format output as env, export, json, sh, fish, pwsh, docker, k8s-secret, k8s-configmap, github-env, gitlab-dotenv, systemd or systemd-dropin, default 'env'"))
+
+        .args(&output_args())
+
+        .args(&key_args())
+
+        .args(&key_filter_args())
+
+        .args(&merge_args())
+
+        .arg(Arg::with_name("systemd-unit")
+            .required(false)
+            .long("systemd-unit")
             .takes_value(true)
             .empty_values(false)
-            .env("PROVIDE_APPLICATION")
-            .value_name("APPLICATION")
-            .help("The application used in path /<application>/<target>/"))
+            .value_name("UNIT")
+            .help("With systemd-dropin, write a drop-in for UNIT, e.g. myapp.service"))
 
-        .arg(Arg::with_name("target")
+        .arg(Arg::with_name("systemd-dir")
             .required(false)
-            .short("t")
-            .long("target")
+            .long("systemd-dir")
             .takes_value(true)
             .empty_values(false)
-            .env("PROVIDE_TARGET")
-            .value_name("TARGET")
-            .help("The target environment used in path /<application>/<target>/"))
+            .value_name("DIR")
+            .help("With systemd-dropin, the directory holding unit drop-ins, default '/etc/systemd/system'"))
+
+        .arg(Arg::with_name("github-mask")
+            .required(false)
+            .long("github-mask")
+            .takes_value(false)
+            .help("With github-env, also print ::add-mask:: commands for SecureString values to stderr"))
 
-        .arg(Arg::with_name("profile")
+        .arg(Arg::with_name("k8s-name")
             .required(false)
-            .short("p")
-            .long("profile")
+            .long("k8s-name")
             .takes_value(true)
+            .empty_values(false)
             .value_name("NAME")
-            .help("Use credentials and region from a local profile"))
+            .help("Name of Kubernetes manifests, default '<application>-<target>' or 'provide'"))
 
-        .arg(Arg::with_name("region")
+        .arg(Arg::with_name("k8s-namespace")
             .required(false)
-            .short("r")
-            .long("region")
+            .long("k8s-namespace")
             .takes_value(true)
-            .value_name("REGION")
-            .env("AWS_REGION")
-            .default_value("us-west-1")
-            .help("Specify region"))
+            .empty_values(false)
+            .value_name("NAMESPACE")
+            .help("Namespace of Kubernetes manifests"))
 
-        .arg(Arg::with_name("include")
+        .arg(Arg::with_name("k8s-label")
             .required(false)
-            .short("i")
-            .long("include")
+            .long("k8s-label")
             .multiple(true)
             .takes_value(true)
             .number_of_values(1)
-            .value_name("FILE")
-            .help("Read env variables in key=value format from a file"))
+            .value_name("KEY=VALUE")
+            .help("Add a label to Kubernetes manifests"))
 
-        .arg(Arg::with_name("merge")
+        .arg(Arg::with_name("k8s-split")
             .required(false)
-            .short("m")
-            .long("merge")
-            .takes_value(true)
+            .long("k8s-split")
+            .takes_value(false)
+            .help("Put SecureString keys in a Secret and the others in a ConfigMap"))
+
+        .arg(Arg::with_name("raw")
+            .required(false)
+            .long("raw")
+            .takes_value(false)
+            .help("Do not base64 encode values on output"))
+
+        .arg(Arg::with_name("secret-files")
+            .required(false)
+            .long("secret-files")
+            .takes_value(false)
+            .help("Give every variable to CMD as a file in PROVIDE_SECRETS_DIR instead of its environment"))
+
+        .arg(Arg::with_name("secret-file")
+            .required(false)
+            .long("secret-file")
             .multiple(true)
+            .takes_value(true)
             .number_of_values(1)
-            .value_name("FILE")
-            .help("Provide initial set of variables and execute FILE, merging output into list of variables"))
+            .conflicts_with("secret-files")
+            .value_name("KEY")
+            .help("Give KEY to CMD as a file in PROVIDE_SECRETS_DIR instead of its environment"))
 
-        .arg(Arg::with_name("format")
+        .arg(Arg::with_name("secrets-dir")
             .required(false)
-            .short("f")
-            .long("format")
+            .long("secrets-dir")
             .takes_value(true)
-            .value_name("FORMAT")
-            .help("Format output, default 'env'"))
+            .empty_values(false)
+            .value_name("DIR")
+            .help("Create the secret files directory in DIR, default '/dev/shm'"))
+
+        .arg(Arg::with_name("file-vars")
+            .required(false)
+            .long("file-vars")
+            .takes_value(false)
+            .help("Set <KEY>_FILE to the path of each secret file"))
+
+        .arg(Arg::with_name("redact")
+            .required(false)
+            .long("redact")
+            .takes_value(false)
+            .help("Replace SecureString values in the output of CMD with ***"))
 
-        .arg(Arg::with_name("env-var")
+        .arg(Arg::with_name("redact-key")
             .required(false)
-            .short("e")
-            .long("env-var")
+            .long("redact-key")
             .multiple(true)
             .takes_value(true)
             .number_of_values(1)
-            .value_name("ENV_VAR_NAME")
-            .help("Capture env var"))
+            .value_name("REGEX")
+            .help("Replace values of keys matching REGEX in the output of CMD with ***"))
 
-        .arg(Arg::with_name("env-var-base64")
+        .arg(Arg::with_name("timeout")
             .required(false)
-            .short("b")
-            .long("env-var-base64")
-            .multiple(true)
+            .long("timeout")
             .takes_value(true)
-            .number_of_values(1)
-            .value_name("ENV_VAR_NAME")
-            .help("Capture env var where var is base64"))
+            .value_name("DURATION")
+            .help("Stop CMD with SIGTERM when it runs for longer than DURATION, failing"))
 
-        .arg(Arg::with_name("raw")
+        .arg(Arg::with_name("watch")
             .required(false)
-            .long("raw")
+            .long("watch")
+            .takes_value(true)
+            .requires("cmd")
+            .value_name("INTERVAL")
+            .help("Resolve variables again every INTERVAL, e.g. 30s or 5m, restarting CMD when they change"))
+
+        .arg(Arg::with_name("signal")
+            .required(false)
+            .long("signal")
+            .takes_value(true)
+            .requires_all(&["watch", "output"])
+            .value_name("SIGNAL")
+            .help("With --watch, send SIGNAL, e.g. HUP, to CMD instead of restarting it. CMD keeps its old environment, so it has to reload --output or its secret files"))
+
+        .arg(Arg::with_name("restart")
+            .required(false)
+            .long("restart")
+            .takes_value(true)
+            .requires("cmd")
+            .possible_values(&["on-failure", "always"])
+            .value_name("POLICY")
+            .help("Start CMD again, with variables resolved again, when it fails or whenever it exits"))
+
+        .arg(Arg::with_name("max-restarts")
+            .required(false)
+            .long("max-restarts")
+            .takes_value(true)
+            .requires("restart")
+            .value_name("COUNT")
+            .help("With --restart, stop restarting CMD after COUNT restarts"))
+
+        .arg(Arg::with_name("restart-delay")
+            .required(false)
+            .long("restart-delay")
+            .takes_value(true)
+            .requires("restart")
+            .value_name("DURATION")
+            .help("With --restart, wait DURATION before the first restart, doubling with every other one, default '1s'"))
+
+        .arg(Arg::with_name("max-restart-delay")
+            .required(false)
+            .long("max-restart-delay")
+            .takes_value(true)
+            .requires("restart")
+            .value_name("DURATION")
+            .help("With --restart, wait at most DURATION between restarts, default '1m'"))
+
+        .arg(Arg::with_name("sequential")
+            .required(false)
+            .long("sequential")
             .takes_value(false)
-            .help("Do not base64 encode values on output"))
+            .conflicts_with_all(&["concurrent", "watch", "restart"])
+            .help("Run each command of CMD, separated by --, one after the other"))
+
+        .arg(Arg::with_name("concurrent")
+            .required(false)
+            .long("concurrent")
+            .takes_value(false)
+            .conflicts_with_all(&["watch", "restart"])
+            .help("Run each command of CMD, or of the --procfile, separated by --, at the same time"))
+
+        .arg(Arg::with_name("procfile")
+            .required(false)
+            .long("procfile")
+            .takes_value(true)
+            .conflicts_with_all(&["cmd", "watch", "restart"])
+            .value_name("FILE")
+            .help("Run the commands in FILE, given as lines of NAME: COMMAND, one after the other unless --concurrent"))
+
+        .arg(Arg::with_name("keep-going")
+            .required(false)
+            .long("keep-going")
+            .takes_value(false)
+            .help("With several commands, keep running the others when one fails"))
 
         // Captures the trailing var args, if any
         .arg(Arg::with_name("cmd")
@@ -151,6 +288,587 @@ fn app<'a, 'b>() -> App<'a, 'b> {
             .multiple(true)
             .value_name("CMD")
             .help("Provide vars to given command"))
+
+        .subcommand(diff_command())
+
+        .subcommand(promote_command())
+
+        .subcommand(list_command())
+
+        .subcommand(sync_command())
+
+        .subcommand(history_command())
+
+        .subcommand(rollback_command())
+
+        .subcommand(render_command())
+
+        .subcommand(explain_command())
+}
+
+// Args selecting where variables are read from, shared by commands running the pipeline
+fn source_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+  vec![
+    Arg::with_name("get")
+      .long("get")
+      .takes_value(false)
+      .requires_all(&["application", "target"])
+      .help("Read AWS vars"),
+    Arg::with_name("decrypt")
+      .long("decrypt")
+      .takes_value(false)
+      .requires("get")
+      .help("Decrypt SecureStrings read with --get, which requires kms:Decrypt"),
+    application_arg(),
+    target_arg(),
+    profile_arg(),
+    region_arg(),
+    Arg::with_name("param")
+      .required(false)
+      .long("param")
+      .multiple(true)
+      .takes_value(true)
+      .number_of_values(1)
+      .value_name("NAME[=ENV_VAR_NAME]")
+      .help("Read a single parameter by its full name, optionally renamed"),
+    Arg::with_name("include")
+      .required(false)
+      .short("i")
+      .long("include")
+      .multiple(true)
+      .takes_value(true)
+      .number_of_values(1)
+      .value_name("FILE")
+      .help("Read env variables in key=value format from a file"),
+    Arg::with_name("merge")
+      .required(false)
+      .short("m")
+      .long("merge")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1)
+      .value_name("COMMAND")
+      .help(
+        "Run COMMAND with the variables read so far, merging its output into them; arguments are quoted as in a shell",
+      ),
+    Arg::with_name("env-var")
+      .required(false)
+      .short("e")
+      .long("env-var")
+      .multiple(true)
+      .takes_value(true)
+      .number_of_values(1)
+      .value_name("ENV_VAR_NAME")
+      .help("Capture env var"),
+    Arg::with_name("env-var-base64")
+      .required(false)
+      .short("b")
+      .long("env-var-base64")
+      .multiple(true)
+      .takes_value(true)
+      .number_of_values(1)
+      .value_name("ENV_VAR_NAME")
+      .help("Capture env var where var is base64"),
+  ]
+}
+
+fn output_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+  vec![
+    Arg::with_name("output")
+      .required(false)
+      .short("o")
+      .long("output")
+      .alias("out")
+      .takes_value(true)
+      .empty_values(false)
+      .value_name("PATH")
+      .help("Atomically write output to PATH instead of stdout"),
+    Arg::with_name("output-mode")
+      .required(false)
+      .long("output-mode")
+      .takes_value(true)
+      .requires("output")
+      .value_name("MODE")
+      .help("Permissions of the --output file, default '0600'"),
+    Arg::with_name("owner")
+      .required(false)
+      .long("owner")
+      .takes_value(true)
+      .requires("output")
+      .value_name("USER")
+      .help("Owner of the --output file"),
+    Arg::with_name("group")
+      .required(false)
+      .long("group")
+      .takes_value(true)
+      .requires("output")
+      .value_name("GROUP")
+      .help("Group of the --output file"),
+  ]
+}
+
+fn key_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+  vec![
+    Arg::with_name("key-case")
+      .required(false)
+      .long("key-case")
+      .takes_value(true)
+      .possible_values(&["upper", "lower", "preserve"])
+      .value_name("CASE")
+      .help("Change the case of keys in every format and for CMD, default 'preserve'"),
+    Arg::with_name("strip-prefix")
+      .required(false)
+      .long("strip-prefix")
+      .takes_value(true)
+      .empty_values(false)
+      .value_name("PREFIX")
+      .help("Remove PREFIX from keys starting with it"),
+    Arg::with_name("key-prefix")
+      .required(false)
+      .long("key-prefix")
+      .takes_value(true)
+      .empty_values(false)
+      .value_name("PREFIX")
+      .help("Add PREFIX to every key"),
+    Arg::with_name("rename")
+      .required(false)
+      .long("rename")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1)
+      .value_name("FROM=TO")
+      .help("Rename key FROM to exactly TO, skipping other key changes"),
+    Arg::with_name("replace-invalid")
+      .required(false)
+      .long("replace-invalid")
+      .takes_value(true)
+      .empty_values(false)
+      .value_name("CHAR")
+      .help("Replace characters in keys other than letters, digits and _ with CHAR, e.g. '_'"),
+  ]
+}
+
+fn key_filter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+  vec![
+    Arg::with_name("only")
+      .required(false)
+      .long("only")
+      .multiple(true)
+      .takes_value(true)
+      .number_of_values(1)
+      .value_name("REGEX")
+      .help("Only provide keys matching REGEX"),
+    Arg::with_name("except")
+      .required(false)
+      .long("except")
+      .multiple(true)
+      .takes_value(true)
+      .number_of_values(1)
+      .value_name("REGEX")
+      .help("Do not provide keys matching REGEX"),
+    Arg::with_name("filter-file")
+      .required(false)
+      .long("filter-file")
+      .takes_value(true)
+      .empty_values(false)
+      .value_name("FILE")
+      .help("Read more --only and --except patterns from lines of only=REGEX or except=REGEX"),
+  ]
+}
+
+fn merge_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+  vec![
+    Arg::with_name("strict-merge")
+      .required(false)
+      .long("strict-merge")
+      .takes_value(true)
+      .possible_values(&["warn", "error"])
+      .value_name("MODE")
+      .help("Warn or fail when a later source overrides the value of an earlier one"),
+    Arg::with_name("lock")
+      .required(false)
+      .long("lock")
+      .multiple(true)
+      .takes_value(true)
+      .number_of_values(1)
+      .value_name("SOURCE:REGEX")
+      .help(
+        "Fail when keys matching REGEX from get, param, include, env-var or merge are overridden",
+      ),
+    Arg::with_name("merge-report")
+      .required(false)
+      .long("merge-report")
+      .takes_value(false)
+      .help("Print the source that set each key to stderr"),
+    Arg::with_name("merge-format")
+      .required(false)
+      .long("merge-format")
+      .takes_value(true)
+      .possible_values(&["raw", "base64", "json"])
+      .value_name("FORMAT")
+      .help("Read --merge output as key=value lines, key=value lines with base64 values or a JSON object, default 'base64'"),
+    Arg::with_name("merge-input")
+      .required(false)
+      .long("merge-input")
+      .takes_value(true)
+      .possible_values(&["env", "json"])
+      .value_name("INPUT")
+      .help("Give --merge commands the variables in their environment or on stdin as a JSON object, default 'env'"),
+    Arg::with_name("merge-timeout")
+      .required(false)
+      .long("merge-timeout")
+      .takes_value(true)
+      .value_name("DURATION")
+      .help("Stop --merge commands with SIGTERM when they run for longer than DURATION, failing"),
+    Arg::with_name("grace-period")
+      .required(false)
+      .long("grace-period")
+      .takes_value(true)
+      .value_name("DURATION")
+      .help("Kill commands still running DURATION after SIGTERM, default '10s'"),
+  ]
+}
+
+fn application_arg<'a, 'b>() -> Arg<'a, 'b> {
+  Arg::with_name("application")
+    .required(false)
+    .short("a")
+    .long("application")
+    .takes_value(true)
+    .empty_values(false)
+    .env("PROVIDE_APPLICATION")
+    .value_name("APPLICATION")
+    .help("The application used in path /<application>/<target>/")
+}
+
+fn target_arg<'a, 'b>() -> Arg<'a, 'b> {
+  Arg::with_name("target")
+    .required(false)
+    .short("t")
+    .long("target")
+    .takes_value(true)
+    .empty_values(false)
+    .env("PROVIDE_TARGET")
+    .value_name("TARGET")
+    .help("The target environment used in path /<application>/<target>/")
+}
+
+fn profile_arg<'a, 'b>() -> Arg<'a, 'b> {
+  Arg::with_name("profile")
+    .required(false)
+    .short("p")
+    .long("profile")
+    .takes_value(true)
+    .value_name("NAME")
+    .help("Use credentials and region from a local profile")
+}
+
+fn region_arg<'a, 'b>() -> Arg<'a, 'b> {
+  Arg::with_name("region")
+    .required(false)
+    .short("r")
+    .long("region")
+    .takes_value(true)
+    .value_name("REGION")
+    .env("AWS_REGION")
+    .default_value("us-west-1")
+    .help("Specify region")
+}
+
+fn diff_command<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name("diff")
+        .about("Compares the variables of a target against another target or a file, exiting non-zero when they differ")
+
+        .arg(application_arg().required(true))
+
+        .arg(target_arg().required(true))
+
+        .arg(profile_arg())
+
+        .arg(region_arg())
+
+        .arg(Arg::with_name("against")
+            .required(false)
+            .long("against")
+            .takes_value(true)
+            .empty_values(false)
+            .value_name("TARGET")
+            .help("Compare against the target environment in path /<application>/<TARGET>/"))
+
+        .arg(Arg::with_name("include")
+            .required(false)
+            .short("i")
+            .long("include")
+            .multiple(true)
+            .takes_value(true)
+            .number_of_values(1)
+            .value_name("FILE")
+            .help("Compare against env variables in key=value format from a file"))
+
+        .group(ArgGroup::with_name("other")
+            .args(&["against", "include"])
+            .required(true))
+
+        .arg(show_values_arg())
+}
+
+fn promote_command<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name("promote")
+    .alias("copy")
+    .about("Copies parameters from one target to another, preserving their type and KMS key")
+    .arg(application_arg().required(true))
+    .arg(profile_arg())
+    .arg(region_arg())
+    .arg(
+      Arg::with_name("from")
+        .required(true)
+        .long("from")
+        .takes_value(true)
+        .empty_values(false)
+        .value_name("TARGET")
+        .help("The target environment to copy from"),
+    )
+    .arg(
+      Arg::with_name("to")
+        .required(true)
+        .long("to")
+        .takes_value(true)
+        .empty_values(false)
+        .value_name("TARGET")
+        .help("The target environment to copy to"),
+    )
+    .arg(
+      Arg::with_name("only")
+        .required(false)
+        .long("only")
+        .multiple(true)
+        .takes_value(true)
+        .number_of_values(1)
+        .value_name("REGEX")
+        .help("Only copy keys matching REGEX"),
+    )
+    .arg(
+      Arg::with_name("except")
+        .required(false)
+        .long("except")
+        .multiple(true)
+        .takes_value(true)
+        .number_of_values(1)
+        .value_name("REGEX")
+        .help("Do not copy keys matching REGEX"),
+    )
+    .arg(
+      Arg::with_name("dry-run")
+        .required(false)
+        .long("dry-run")
+        .takes_value(false)
+        .help("Show what would be copied without writing anything"),
+    )
+    .arg(
+      Arg::with_name("yes")
+        .required(false)
+        .short("y")
+        .long("yes")
+        .takes_value(false)
+        .help("Overwrite existing keys without asking for confirmation"),
+    )
+}
+
+fn list_command<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name("list")
+    .about("Lists the parameters of a target with their metadata, without values")
+    .arg(application_arg().required(true))
+    .arg(target_arg().required(true))
+    .arg(profile_arg())
+    .arg(region_arg())
+    .arg(report_format_arg())
+}
+
+fn sync_command<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name("sync")
+    .about("Synchronizes a target with local files, treating the files as the source of truth")
+    .arg(application_arg().required(true))
+    .arg(target_arg().required(true))
+    .arg(profile_arg())
+    .arg(region_arg())
+    .arg(
+      Arg::with_name("include")
+        .required(true)
+        .short("i")
+        .long("include")
+        .multiple(true)
+        .takes_value(true)
+        .number_of_values(1)
+        .value_name("FILE")
+        .help("Read env variables in key=value format from a file"),
+    )
+    // Pruning is currently the only sync operation, so it must be asked for
+    .arg(
+      Arg::with_name("prune")
+        .required(true)
+        .long("prune")
+        .takes_value(false)
+        .help("Delete parameters of the target whose keys are not in any FILE"),
+    )
+    .arg(
+      Arg::with_name("dry-run")
+        .required(false)
+        .long("dry-run")
+        .takes_value(false)
+        .help("Show what would be deleted without deleting anything"),
+    )
+    .arg(
+      Arg::with_name("yes")
+        .required(false)
+        .short("y")
+        .long("yes")
+        .takes_value(false)
+        .help("Delete without asking for confirmation"),
+    )
+}
+
+fn history_command<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name("history")
+    .about("Shows every version of a parameter with masked values")
+    .arg(application_arg().required(true))
+    .arg(target_arg().required(true))
+    .arg(profile_arg())
+    .arg(region_arg())
+    .arg(show_values_arg())
+    .arg(report_format_arg())
+    .arg(
+      Arg::with_name("key")
+        .required(true)
+        .value_name("KEY")
+        .help("The key in path /<application>/<target>/<KEY>"),
+    )
+}
+
+fn rollback_command<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name("rollback")
+        .about("Re-puts the value a parameter, or every parameter of a target, had at an earlier version or date. Parameters deleted since cannot be restored, as Parameter Store deletes their history with them")
+
+        .arg(application_arg().required(true))
+
+        .arg(target_arg().required(true))
+
+        .arg(profile_arg())
+
+        .arg(region_arg())
+
+        .arg(Arg::with_name("to-version")
+            .required(false)
+            .long("to-version")
+            .takes_value(true)
+            .requires("key")
+            .value_name("VERSION")
+            .help("Roll KEY back to VERSION"))
+
+        .arg(Arg::with_name("to-date")
+            .required(false)
+            .long("to-date")
+            .takes_value(true)
+            .value_name("DATE")
+            .help("Roll back to the values at DATE, e.g. 2022-05-01T10:00:00Z"))
+
+        .group(ArgGroup::with_name("point")
+            .args(&["to-version", "to-date"])
+            .required(true))
+
+        .arg(Arg::with_name("delete-created")
+            .required(false)
+            .long("delete-created")
+            .takes_value(false)
+            .requires("to-date")
+            .help("Also delete parameters of the target created after DATE"))
+
+        .arg(Arg::with_name("dry-run")
+            .required(false)
+            .long("dry-run")
+            .takes_value(false)
+            .help("Show what would be rolled back without writing anything"))
+
+        .arg(Arg::with_name("yes")
+            .required(false)
+            .short("y")
+            .long("yes")
+            .takes_value(false)
+            .help("Roll back without asking for confirmation"))
+
+        .arg(Arg::with_name("key")
+            .required(false)
+            .value_name("KEY")
+            .help("The key in path /<application>/<target>/<KEY>, every key of the target if omitted"))
+}
+
+fn render_command<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name("render")
+    .about("Renders a template, replacing {{ KEY }} and {{ KEY | base64 }}, {{ KEY | json }} or {{ KEY | url }} with values")
+    .args(&source_args())
+    .args(&output_args())
+    .args(&key_args())
+    .args(&key_filter_args())
+    .args(&merge_args())
+    .arg(
+      Arg::with_name("template")
+        .required(true)
+        .long("template")
+        .takes_value(true)
+        .value_name("FILE")
+        .help("The template to render"),
+    )
+    .arg(
+      Arg::with_name("strict")
+        .required(false)
+        .long("strict")
+        .takes_value(false)
+        .help("Fail when the template uses undefined variables"),
+    )
+}
+
+fn explain_command<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name("explain")
+    .about("Shows the source of every variable, and the sources it overrode, with masked values")
+    .args(&source_args())
+    .args(&key_args())
+    .args(&key_filter_args())
+    .args(&merge_args())
+    .arg(show_values_arg())
+}
+
+fn show_values_arg<'a, 'b>() -> Arg<'a, 'b> {
+  Arg::with_name("show-values")
+    .required(false)
+    .long("show-values")
+    .takes_value(true)
+    .possible_values(&["hash", "full"])
+    .value_name("MODE")
+    .help("Show value fingerprints, only comparable within one run, or full values instead of masking them")
+}
+
+fn report_format_arg<'a, 'b>() -> Arg<'a, 'b> {
+  Arg::with_name("format")
+    .required(false)
+    .short("f")
+    .long("format")
+    .takes_value(true)
+    .possible_values(&["table", "json"])
+    .value_name("FORMAT")
+    .help("Format output, default 'table'")
+}
+
+// Sets the AWS environment used by the sdk from --region and --profile
+fn set_aws_env(matches: &ArgMatches) {
+  let region_name = matches.value_of("region");
+  if let Some(name) = region_name {
+    // Ensure AWS_REGION is the same
+    env::set_var("AWS_REGION", name);
+  }
+
+  let profile = matches.value_of("profile");
+  if let Some(name) = profile {
+    env::set_var("AWS_PROFILE", name);
+  }
 }
 
 fn options_from_matches(matches: ArgMatches) -> Result<ProcessParametersOptions, Error> {
@@ -174,58 +892,98 @@ fn options_from_matches(matches: ArgMatches) -> Result<ProcessParametersOptions,
     _ => None,
   };
 
-  let region_name = matches.value_of("region");
-  if let Some(name) = region_name {
-    // Ensure AWS_REGION is the same
-    env::set_var("AWS_REGION", name);
-  }
+  set_aws_env(&matches);
 
-  let includes: Option<Vec<String>> = match matches.values_of("include") {
-    Some(values) => Some(values.map(|val| String::from(val)).collect()),
-    None => None,
-  };
+  let includes: Option<Vec<String>> = matches
+    .values_of("include")
+    .map(|values| values.map(String::from).collect());
 
-  let merges: Option<Vec<String>> = match matches.values_of("merge") {
-    Some(values) => Some(values.map(|val| String::from(val)).collect()),
-    None => None,
-  };
+  let params: Option<Vec<String>> = matches
+    .values_of("param")
+    .map(|values| values.map(String::from).collect());
 
-  let profile = matches.value_of("profile");
-  if profile.is_some() {
-    env::set_var("AWS_PROFILE", profile.unwrap());
-  }
+  let merges: Option<Vec<String>> = matches
+    .values_of("merge")
+    .map(|values| values.map(String::from).collect());
 
   let format = match matches.value_of("format") {
     Some("export") => Ok(Format::EXPORT),
     Some("json") => Ok(Format::JSON),
+    Some("docker") => Ok(Format::DOCKER),
+    Some("k8s-secret") => Ok(Format::K8S_SECRET),
+    Some("k8s-configmap") => Ok(Format::K8S_CONFIGMAP),
+    Some("sh") => Ok(Format::SH),
+    Some("fish") => Ok(Format::FISH),
+    Some("pwsh") => Ok(Format::PWSH),
+    Some("github-env") => Ok(Format::GITHUB_ENV),
+    Some("gitlab-dotenv") => Ok(Format::GITLAB_DOTENV),
+    Some("systemd") => Ok(Format::SYSTEMD),
+    Some("systemd-dropin") => Ok(Format::SYSTEMD_DROPIN),
     Some("env") | None => Ok(Format::ENV),
     Some(format_name) => Err(Error::BadFormat(format!("Unknown format {format_name}",))),
   }?;
 
   let raw = matches.is_present("raw");
 
-  let format_config = FormatConfig { format, raw };
+  let manifest = manifest_config_from_matches(&matches, &app, &target)?;
 
-  let env_vars: Option<Vec<String>> = match matches.values_of("env-var") {
-    Some(values) => Some(values.map(|v| v.to_owned()).collect()),
-    None => None,
-  };
+  let github_mask = matches.is_present("github-mask");
 
-  let env_vars_base64: Option<Vec<String>> = match matches.values_of("env-var-base64") {
-    Some(values) => Some(values.map(|v| v.to_owned()).collect()),
+  let systemd_unit = matches.value_of("systemd-unit").map(String::from);
+  let systemd_dir = matches.value_of("systemd-dir").map(String::from);
+  if format == Format::SYSTEMD_DROPIN && systemd_unit.is_none() {
+    return Err(Error::BadFormat(String::from(
+      "Format systemd-dropin requires --systemd-unit",
+    )));
+  }
+
+  let output = match matches.value_of("output") {
+    Some(path) => Some(OutputConfig {
+      path: path.to_owned(),
+      mode: matches
+        .value_of("output-mode")
+        .map(api::parse_mode)
+        .transpose()?,
+      owner: matches.value_of("owner").map(String::from),
+      group: matches.value_of("group").map(String::from),
+    }),
     None => None,
   };
 
-  let cmds: Option<Vec<String>> = match matches.values_of("cmd") {
-    Some(vals) => Some(vals.map(|v| String::from(v)).collect()),
-    None => None,
+  let format_config = FormatConfig {
+    format,
+    raw,
+    manifest,
+    github_mask,
+    systemd_unit,
+    systemd_dir,
+    output,
   };
 
+  let key_filter = key_filter_from_matches(&matches)?;
+
+  let key_transform = key_transform_from_matches(&matches)?;
+
+  let merge_config = merge_config_from_matches(&matches)?;
+
+  let env_vars: Option<Vec<String>> = matches
+    .values_of("env-var")
+    .map(|values| values.map(|v| v.to_owned()).collect());
+
+  let env_vars_base64: Option<Vec<String>> = matches
+    .values_of("env-var-base64")
+    .map(|values| values.map(|v| v.to_owned()).collect());
+
+  let cmds: Option<Vec<String>> = matches
+    .values_of("cmd")
+    .map(|vals| vals.map(String::from).collect());
+
   let run_config = match cmds {
     Some(vars) => match vars.split_at(1) {
       ([head], tail) => Some(RunConfig {
         cmd: head.to_owned(),
         args: tail.to_owned(),
+        ..command_config_from_matches(&matches)?
       }),
       _ => None,
     },
@@ -234,25 +992,632 @@ fn options_from_matches(matches: ArgMatches) -> Result<ProcessParametersOptions,
 
   Ok(ProcessParametersOptions {
     app,
+    decrypt: matches.is_present("decrypt"),
     env_vars,
     env_vars_base64,
     format_config,
     includes,
+    key_filter,
+    key_transform,
+    merge_config,
     merges,
     mode,
+    params,
     path,
     run_config,
     target,
   })
 }
 
-fn display(format_config: FormatConfig, map: HashMap<String, String>) {
-  let formatted = match format_config.format {
-    Format::ENV => api::as_env_format(map, format_config.raw),
-    Format::EXPORT => api::as_export_format(map, format_config.raw),
-    Format::JSON => unimplemented!(),
+fn supervisor_config_from_matches(matches: &ArgMatches) -> Result<SupervisorConfig, Error> {
+  Ok(SupervisorConfig {
+    watch: watch_config_from_matches(matches)?,
+    restart: restart_config_from_matches(matches)?,
+  })
+}
+
+fn restart_config_from_matches(matches: &ArgMatches) -> Result<RestartConfig, Error> {
+  let defaults = RestartConfig::default();
+  let policy = match matches.value_of("restart") {
+    Some("on-failure") => RestartPolicy::ON_FAILURE,
+    Some("always") => RestartPolicy::ALWAYS,
+    _ => RestartPolicy::NEVER,
+  };
+  let max_restarts = matches
+    .value_of("max-restarts")
+    .map(|count| {
+      count
+        .parse()
+        .map_err(|_| Error::BadFormat(format!("Invalid restart count {count}")))
+    })
+    .transpose()?;
+  let delay = match matches.value_of("restart-delay") {
+    Some(delay) => api::parse_duration(delay)?,
+    None => defaults.delay,
+  };
+  let max_delay = match matches.value_of("max-restart-delay") {
+    Some(delay) => api::parse_duration(delay)?,
+    None => defaults.max_delay,
+  };
+  Ok(RestartConfig {
+    policy,
+    max_restarts,
+    delay,
+    max_delay,
+  })
+}
+
+fn watch_config_from_matches(matches: &ArgMatches) -> Result<Option<WatchConfig>, Error> {
+  let interval = match matches.value_of("watch") {
+    Some(interval) => api::parse_duration(interval)?,
+    None => return Ok(None),
+  };
+  let signal = matches.value_of("signal").map(String::from);
+  if let Some(name) = &signal {
+    api::parse_signal(name)?;
+  }
+  Ok(Some(WatchConfig { interval, signal }))
+}
+
+fn merge_config_from_matches(matches: &ArgMatches) -> Result<MergeConfig, Error> {
+  let strict = match matches.value_of("strict-merge") {
+    Some("warn") => StrictMerge::WARN,
+    Some("error") => StrictMerge::ERROR,
+    _ => StrictMerge::OFF,
+  };
+  let locked = matches
+    .values_of("lock")
+    .into_iter()
+    .flatten()
+    .map(|lock| match lock.split_once(':') {
+      Some((kind, pattern)) if !pattern.is_empty() => Ok((kind.to_owned(), pattern.to_owned())),
+      _ => Err(Error::BadFormat(format!(
+        "Invalid --lock {lock}, expected SOURCE:REGEX"
+      ))),
+    })
+    .collect::<Result<_, Error>>()?;
+  let format = match matches.value_of("merge-format") {
+    Some("raw") => MergeFormat::RAW,
+    Some("json") => MergeFormat::JSON,
+    _ => MergeFormat::BASE64,
+  };
+  let input = match matches.value_of("merge-input") {
+    Some("json") => MergeInput::JSON,
+    _ => MergeInput::ENV,
+  };
+  Ok(MergeConfig {
+    strict,
+    locked,
+    format,
+    input,
+    timeout: duration_from_matches(matches, "merge-timeout")?,
+    grace_period: duration_from_matches(matches, "grace-period")?,
+  })
+}
+
+fn duration_from_matches(matches: &ArgMatches, name: &str) -> Result<Option<Duration>, Error> {
+  matches.value_of(name).map(api::parse_duration).transpose()
+}
+
+fn key_filter_from_matches(matches: &ArgMatches) -> Result<KeyFilter, Error> {
+  let file_filter = match matches.value_of("filter-file") {
+    Some(path) => api::read_key_filter_file(path)?,
+    None => KeyFilter::default(),
+  };
+  let combine = |from_file: Option<Vec<String>>, name: &str| -> Option<Vec<String>> {
+    let patterns: Vec<String> = from_file
+      .into_iter()
+      .flatten()
+      .chain(
+        matches
+          .values_of(name)
+          .into_iter()
+          .flatten()
+          .map(String::from),
+      )
+      .collect();
+    (!patterns.is_empty()).then_some(patterns)
+  };
+  Ok(KeyFilter {
+    only: combine(file_filter.only, "only"),
+    except: combine(file_filter.except, "except"),
+  })
+}
+
+fn key_transform_from_matches(matches: &ArgMatches) -> Result<KeyTransform, Error> {
+  let case = match matches.value_of("key-case") {
+    Some("upper") => KeyCase::UPPER,
+    Some("lower") => KeyCase::LOWER,
+    _ => KeyCase::PRESERVE,
+  };
+  let renames = matches
+    .values_of("rename")
+    .map(|values| {
+      values
+        .map(|rename| match rename.split_once('=') {
+          Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+            Ok((from.to_owned(), to.to_owned()))
+          }
+          _ => Err(Error::BadFormat(format!(
+            "Invalid --rename {rename}, expected FROM=TO"
+          ))),
+        })
+        .collect::<Result<Vec<_>, Error>>()
+    })
+    .transpose()?
+    .unwrap_or_default();
+  let replace_invalid = match matches.value_of("replace-invalid") {
+    Some(replacement) => {
+      let mut chars = replacement.chars();
+      match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => {
+          return Err(Error::BadFormat(format!(
+            "Invalid --replace-invalid {replacement}, expected a single character"
+          )))
+        }
+      }
+    }
+    None => None,
+  };
+  Ok(KeyTransform {
+    case,
+    strip_prefix: matches.value_of("strip-prefix").map(String::from),
+    prefix: matches.value_of("key-prefix").map(String::from),
+    renames,
+    replace_invalid,
+  })
+}
+
+// How commands are run, without the command itself
+fn command_config_from_matches(matches: &ArgMatches) -> Result<RunConfig, Error> {
+  let secret_files = match (
+    matches.is_present("secret-files"),
+    matches.values_of("secret-file"),
+  ) {
+    (true, _) => Some(SecretFilesConfig {
+      keys: None,
+      ..SecretFilesConfig::default()
+    }),
+    (false, Some(keys)) => Some(SecretFilesConfig {
+      keys: Some(keys.map(String::from).collect()),
+      ..SecretFilesConfig::default()
+    }),
+    (false, None) => None,
+  }
+  .map(|secret_files| SecretFilesConfig {
+    dir: matches.value_of("secrets-dir").map(String::from),
+    file_vars: matches.is_present("file-vars"),
+    ..secret_files
+  });
+
+  let redact_keys: Vec<String> = matches
+    .values_of("redact-key")
+    .map(|values| values.map(String::from).collect())
+    .unwrap_or_default();
+  let redact = match (matches.is_present("redact"), redact_keys.is_empty()) {
+    (false, true) => None,
+    (secure, _) => Some(RedactConfig {
+      secure,
+      keys: redact_keys,
+    }),
+  };
+
+  Ok(RunConfig {
+    secret_files,
+    redact,
+    timeout: duration_from_matches(matches, "timeout")?,
+    grace_period: duration_from_matches(matches, "grace-period")?,
+    ..RunConfig::default()
+  })
+}
+
+/*
+    Commands from the --procfile, or from CMD split at each -- with
+    --sequential or --concurrent. None when there is a single CMD to run.
+*/
+fn process_list_from_matches(matches: &ArgMatches) -> Result<Option<ProcessList>, Error> {
+  let template = command_config_from_matches(matches)?;
+  let commands: Vec<RunConfig> = if let Some(path) = matches.value_of("procfile") {
+    api::read_procfile(path)?
+      .into_iter()
+      .map(|(name, command)| RunConfig {
+        cmd: String::from("sh"),
+        args: vec![String::from("-c"), command],
+        name: Some(name),
+        ..template.clone()
+      })
+      .collect()
+  } else if matches.is_present("sequential") || matches.is_present("concurrent") {
+    let cmds: Vec<&str> = matches.values_of("cmd").into_iter().flatten().collect();
+    cmds
+      .split(|arg| *arg == "--")
+      .filter(|command| !command.is_empty())
+      .map(|command| RunConfig {
+        cmd: command[0].to_owned(),
+        args: command[1..].iter().map(|arg| arg.to_string()).collect(),
+        name: Path::new(command[0])
+          .file_name()
+          .map(|name| name.to_string_lossy().into_owned()),
+        ..template.clone()
+      })
+      .collect()
+  } else {
+    return Ok(None);
+  };
+  if commands.is_empty() {
+    return Err(Error::Error(String::from("No commands to run")));
+  }
+  let mode = match matches.is_present("concurrent") {
+    true => RunMode::CONCURRENT,
+    false => RunMode::SEQUENTIAL,
+  };
+  Ok(Some(ProcessList {
+    commands: unique_names(commands),
+    mode,
+    keep_going: matches.is_present("keep-going"),
+  }))
+}
+
+// Numbers commands sharing a name so their output can be told apart
+fn unique_names(commands: Vec<RunConfig>) -> Vec<RunConfig> {
+  let mut seen = HashMap::<String, usize>::new();
+  commands
+    .into_iter()
+    .map(|command| {
+      let name = command.name.clone().unwrap_or_default();
+      let count = seen.entry(name.clone()).or_default();
+      *count += 1;
+      match count {
+        1 => command,
+        n => RunConfig {
+          name: Some(format!("{name}.{n}")),
+          ..command
+        },
+      }
+    })
+    .collect()
+}
+
+fn manifest_config_from_matches(
+  matches: &ArgMatches,
+  app: &Option<String>,
+  target: &Option<String>,
+) -> Result<ManifestConfig, Error> {
+  let name = match (matches.value_of("k8s-name"), app, target) {
+    (Some(name), _, _) => Some(name.to_owned()),
+    (None, Some(a), Some(t)) => Some(format!("{}-{}", a, t).to_lowercase()),
+    (None, Some(a), None) => Some(a.to_lowercase()),
+    _ => None,
   };
+
+  let labels = matches
+    .values_of("k8s-label")
+    .map(|values| {
+      values
+        .map(|label| match label.find('=') {
+          Some(index) if index > 0 => {
+            Ok((label[0..index].to_owned(), label[index + 1..].to_owned()))
+          }
+          _ => Err(Error::BadFormat(format!(
+            "Invalid label {label}, expected KEY=VALUE"
+          ))),
+        })
+        .collect::<Result<Vec<(String, String)>, Error>>()
+    })
+    .transpose()?
+    .unwrap_or_default();
+
+  Ok(ManifestConfig {
+    name,
+    namespace: matches.value_of("k8s-namespace").map(String::from),
+    labels,
+    split: matches.is_present("k8s-split"),
+  })
+}
+
+fn diff_options_from_matches(
+  matches: &ArgMatches,
+) -> Result<
+  (
+    ProcessParametersOptions,
+    ProcessParametersOptions,
+    ShowValues,
+  ),
+  Error,
+> {
+  set_aws_env(matches);
+
+  let app = matches.value_of("application").unwrap_or_default();
+  let target = matches.value_of("target").unwrap_or_default();
+
+  let left = ProcessParametersOptions {
+    mode: Some(Mode::GET),
+    path: Some(format!("/{}/{}", app, target)),
+    ..ProcessParametersOptions::default()
+  };
+
+  let right = match matches.value_of("against") {
+    Some(against) => ProcessParametersOptions {
+      mode: Some(Mode::GET),
+      path: Some(format!("/{}/{}", app, against)),
+      ..ProcessParametersOptions::default()
+    },
+    None => ProcessParametersOptions {
+      includes: matches
+        .values_of("include")
+        .map(|values| values.map(String::from).collect()),
+      ..ProcessParametersOptions::default()
+    },
+  };
+
+  Ok((left, right, show_values_from_matches(matches)))
+}
+
+async fn run_diff(matches: &ArgMatches<'_>) -> Result<(), Error> {
+  let (left, right, show_values) = diff_options_from_matches(matches)?;
+  let left_vars = api::expose_vars(api::process_parameters(left).await?);
+  let right_vars = api::expose_vars(api::process_parameters(right).await?);
+  let differences = api::diff(&left_vars, &right_vars);
+  print!("{}", api::as_diff_format(&differences, show_values));
+  match differences.len() {
+    0 => Ok(()),
+    count => Err(Error::DiffError(format!("{count} keys differ"))),
+  }
+}
+
+fn promote_options_from_matches(matches: &ArgMatches) -> Result<PromoteOptions, Error> {
+  set_aws_env(matches);
+
+  let app = matches.value_of("application").unwrap_or_default();
+  let from = matches.value_of("from").unwrap_or_default();
+  let to = matches.value_of("to").unwrap_or_default();
+
+  if from == to {
+    return Err(Error::Error(format!("Cannot promote {from} to itself")));
+  }
+
+  let key_filter = KeyFilter {
+    only: matches
+      .values_of("only")
+      .map(|values| values.map(String::from).collect()),
+    except: matches
+      .values_of("except")
+      .map(|values| values.map(String::from).collect()),
+  };
+
+  Ok(PromoteOptions {
+    from_path: format!("/{}/{}", app, from),
+    to_path: format!("/{}/{}", app, to),
+    key_filter,
+  })
+}
+
+async fn run_promote(matches: &ArgMatches<'_>) -> Result<(), Error> {
+  let options = promote_options_from_matches(matches)?;
+  let promotions = api::plan_promotion(&options).await?;
+  print!("{}", api::as_promotion_format(&promotions));
+  if matches.is_present("dry-run") || promotions.is_empty() {
+    return Ok(());
+  }
+  let overwrites = promotions.iter().filter(|p| p.overwrite).count();
+  if overwrites > 0 && !matches.is_present("yes") {
+    let prompt = format!(
+      "Overwrite {overwrites} existing keys in {}?",
+      options.to_path
+    );
+    if !confirm(&prompt)? {
+      return Err(Error::Error(String::from("Aborted")));
+    }
+  }
+  api::promote(&promotions).await
+}
+
+fn show_values_from_matches(matches: &ArgMatches) -> ShowValues {
+  match matches.value_of("show-values") {
+    Some("hash") => ShowValues::HASH,
+    Some("full") => ShowValues::FULL,
+    _ => ShowValues::MASKED,
+  }
+}
+
+fn report_format_from_matches(matches: &ArgMatches) -> ReportFormat {
+  match matches.value_of("format") {
+    Some("json") => ReportFormat::JSON,
+    _ => ReportFormat::TABLE,
+  }
+}
+
+async fn run_list(matches: &ArgMatches<'_>) -> Result<(), Error> {
+  set_aws_env(matches);
+  let app = matches.value_of("application").unwrap_or_default();
+  let target = matches.value_of("target").unwrap_or_default();
+  let infos = api::list_parameters(&format!("/{}/{}", app, target)).await?;
+  print!(
+    "{}",
+    api::as_list_format(&infos, report_format_from_matches(matches))?
+  );
+  Ok(())
+}
+
+fn sync_options_from_matches(
+  matches: &ArgMatches,
+) -> Result<(String, ProcessParametersOptions), Error> {
+  set_aws_env(matches);
+
+  let app = matches.value_of("application").unwrap_or_default();
+  let target = matches.value_of("target").unwrap_or_default();
+
+  let local = ProcessParametersOptions {
+    includes: matches
+      .values_of("include")
+      .map(|values| values.map(String::from).collect()),
+    ..ProcessParametersOptions::default()
+  };
+
+  Ok((format!("/{}/{}", app, target), local))
+}
+
+async fn run_sync(matches: &ArgMatches<'_>) -> Result<(), Error> {
+  let (path, local) = sync_options_from_matches(matches)?;
+  let vars = api::expose_vars(api::process_parameters(local).await?);
+  let names = api::plan_prune(&path, &vars).await?;
+  for name in names.iter() {
+    println!("- {name}");
+  }
+  if matches.is_present("dry-run") || names.is_empty() {
+    return Ok(());
+  }
+  if !matches.is_present("yes") {
+    let prompt = format!("Delete {} parameters from {path}?", names.len());
+    if !confirm(&prompt)? {
+      return Err(Error::Error(String::from("Aborted")));
+    }
+  }
+  api::delete_parameters(&names).await
+}
+
+async fn run_history(matches: &ArgMatches<'_>) -> Result<(), Error> {
+  set_aws_env(matches);
+  let app = matches.value_of("application").unwrap_or_default();
+  let target = matches.value_of("target").unwrap_or_default();
+  let key = matches.value_of("key").unwrap_or_default();
+  let history = api::parameter_history(&format!("/{}/{}/{}", app, target, key)).await?;
+  let formatted = api::as_history_format(
+    &history,
+    show_values_from_matches(matches),
+    report_format_from_matches(matches),
+  )?;
   print!("{}", formatted);
+  Ok(())
+}
+
+fn rollback_options_from_matches(
+  matches: &ArgMatches,
+) -> Result<(String, Option<String>, RollbackPoint), Error> {
+  set_aws_env(matches);
+
+  let app = matches.value_of("application").unwrap_or_default();
+  let target = matches.value_of("target").unwrap_or_default();
+  let key = matches.value_of("key").map(String::from);
+
+  let point = match (matches.value_of("to-version"), matches.value_of("to-date")) {
+    (Some(version), _) => RollbackPoint::VERSION(
+      version
+        .parse()
+        .map_err(|_| Error::BadFormat(format!("Invalid version {version}")))?,
+    ),
+    (None, Some(date)) => RollbackPoint::DATE(api::parse_date_time(date)?),
+    (None, None) => return Err(Error::Error(String::from("No version or date given"))),
+  };
+
+  Ok((format!("/{}/{}", app, target), key, point))
+}
+
+async fn run_rollback(matches: &ArgMatches<'_>) -> Result<(), Error> {
+  let (path, key, point) = rollback_options_from_matches(matches)?;
+  let plan = api::plan_rollback(&path, key.as_deref(), point).await?;
+  let delete_created = matches.is_present("delete-created");
+  print!("{}", api::as_rollback_format(&plan, delete_created));
+  let deletions = match delete_created {
+    true => plan.created,
+    false => vec![],
+  };
+  if matches.is_present("dry-run") || (plan.promotions.is_empty() && deletions.is_empty()) {
+    return Ok(());
+  }
+  if !matches.is_present("yes") {
+    let prompt = format!(
+      "Roll back {} parameters and delete {} in {path}?",
+      plan.promotions.len(),
+      deletions.len()
+    );
+    if !confirm(&prompt)? {
+      return Err(Error::Error(String::from("Aborted")));
+    }
+  }
+  api::promote(&plan.promotions).await?;
+  api::delete_parameters(&deletions).await
+}
+
+async fn run_render(matches: &ArgMatches<'_>) -> Result<(), Error> {
+  let options = options_from_matches(matches.clone())?;
+  let output = options.format_config.output.clone();
+  let template = fs::read_to_string(matches.value_of("template").unwrap_or_default())?;
+  let processed = api::process_parameters_detailed(options).await?;
+  report_processed(matches, &processed);
+  let vars = api::expose_vars(processed.vars);
+  let rendered = api::render_template(&template, &vars, matches.is_present("strict"))?;
+  match output {
+    Some(output) => api::write_output(Path::new(&output.path), &rendered, &output),
+    None => {
+      print!("{}", rendered);
+      Ok(())
+    }
+  }
+}
+
+async fn run_explain(matches: &ArgMatches<'_>) -> Result<(), Error> {
+  let options = options_from_matches(matches.clone())?;
+  let processed = api::process_parameters_detailed(options).await?;
+  report_processed(matches, &processed);
+  let explanations = api::explain(&processed, show_values_from_matches(matches));
+  print!("{}", api::as_explain_format(&explanations));
+  Ok(())
+}
+
+fn confirm(prompt: &str) -> Result<bool, Error> {
+  print!("{prompt} [y/N] ");
+  io::stdout().flush()?;
+  let mut answer = String::new();
+  io::stdin().read_line(&mut answer)?;
+  Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+// Prints warnings and the --merge-report to stderr, keeping stdout for output
+fn report_processed(matches: &ArgMatches, processed: &ProcessedParameters) {
+  for pattern in &processed.unmatched_patterns {
+    eprintln!("Warning: pattern {pattern} matched no key");
+  }
+  if matches.value_of("strict-merge") == Some("warn") {
+    for o in &processed.overrides {
+      eprintln!(
+        "Warning: {} from {} is overridden by {}",
+        o.key, o.overridden, o.source
+      );
+    }
+  }
+  if matches.is_present("merge-report") {
+    eprint!("{}", api::as_merge_report_format(&processed.origins));
+  }
+}
+
+fn display(format_config: &FormatConfig, processed: ProcessedParameters) -> Result<(), Error> {
+  let map = api::expose_vars(processed.vars);
+  if format_config.format == Format::GITHUB_ENV && format_config.github_mask {
+    eprint!(
+      "{}",
+      api::as_github_mask_format(&map, &processed.secure_keys)
+    );
+  }
+  if format_config.format == Format::SYSTEMD_DROPIN {
+    let unit = format_config.systemd_unit.as_deref().unwrap_or_default();
+    let dir = format_config.systemd_dir.as_deref();
+    let path = api::write_systemd_dropin(map, unit, dir)?;
+    eprintln!(
+      "Wrote {}, run systemctl daemon-reload to apply",
+      path.display()
+    );
+    return Ok(());
+  }
+  let formatted = api::format_vars(format_config, map, &processed.secure_keys)?;
+  match &format_config.output {
+    Some(output) => api::write_output(Path::new(&output.path), &formatted, output),
+    None => {
+      print!("{}", formatted);
+      Ok(())
+    }
+  }
 }
 
 #[cfg(test)]
@@ -293,6 +1658,42 @@ mod tests {
     );
   }
 
+  #[test]
+  fn test_param_only_accepts_one_value() {
+    let m = app().get_matches_from(vec!["provide", "--param", "/shared/prod/FOO=BAR", "cmd"]);
+    let options = options_from_matches(m);
+    assert_eq!(
+      options.unwrap(),
+      ProcessParametersOptions {
+        params: Some(vec!["/shared/prod/FOO=BAR".to_owned()]),
+        run_config: Some(RunConfig {
+          cmd: "cmd".to_owned(),
+          ..RunConfig::default()
+        }),
+        ..ProcessParametersOptions::default()
+      }
+    );
+  }
+
+  #[test]
+  fn test_decrypt_requires_get() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "--get",
+      "--decrypt",
+      "-a",
+      "app",
+      "-t",
+      "dev",
+    ]);
+    assert!(options_from_matches(m).unwrap().decrypt);
+    let m = app().get_matches_from(vec!["provide", "--get", "-a", "app", "-t", "dev"]);
+    assert!(!options_from_matches(m).unwrap().decrypt);
+    assert!(app()
+      .get_matches_from_safe(vec!["provide", "--decrypt"])
+      .is_err());
+  }
+
   #[test]
   fn test_env_vars_only_accepts_one_value() {
     let m = app().get_matches_from(vec!["provide", "--env-var", "FOO=bar", "cmd"]);
@@ -326,4 +1727,571 @@ mod tests {
       }
     );
   }
+
+  #[test]
+  fn test_diff_against_target() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "diff",
+      "-a",
+      "app",
+      "-t",
+      "staging",
+      "--against",
+      "production",
+    ]);
+    let (_, diff_matches) = m.subcommand();
+    let (left, right, show_values) = diff_options_from_matches(diff_matches.unwrap()).unwrap();
+    assert_eq!(left.path, Some("/app/staging".to_owned()));
+    assert_eq!(right.path, Some("/app/production".to_owned()));
+    assert_eq!(right.mode, Some(Mode::GET));
+    assert_eq!(show_values, ShowValues::MASKED);
+  }
+
+  #[test]
+  fn test_diff_against_include() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "diff",
+      "-a",
+      "app",
+      "-t",
+      "staging",
+      "-i",
+      "file",
+      "--show-values",
+      "hash",
+    ]);
+    let (_, diff_matches) = m.subcommand();
+    let (_, right, show_values) = diff_options_from_matches(diff_matches.unwrap()).unwrap();
+    assert_eq!(right.mode, None);
+    assert_eq!(right.includes, Some(vec!["file".to_owned()]));
+    assert_eq!(show_values, ShowValues::HASH);
+  }
+
+  #[test]
+  fn test_promote_options() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "promote",
+      "-a",
+      "app",
+      "--from",
+      "staging",
+      "--to",
+      "production",
+      "--except",
+      "^DEBUG_",
+    ]);
+    let (_, promote_matches) = m.subcommand();
+    let options = promote_options_from_matches(promote_matches.unwrap()).unwrap();
+    assert_eq!(
+      options,
+      PromoteOptions {
+        from_path: "/app/staging".to_owned(),
+        to_path: "/app/production".to_owned(),
+        key_filter: KeyFilter {
+          only: None,
+          except: Some(vec!["^DEBUG_".to_owned()]),
+        },
+      }
+    );
+  }
+
+  #[test]
+  fn test_promote_rejects_same_target() {
+    let m = app().get_matches_from(vec![
+      "provide", "copy", "-a", "app", "--from", "staging", "--to", "staging",
+    ]);
+    let (name, promote_matches) = m.subcommand();
+    assert_eq!(name, "promote");
+    assert!(promote_options_from_matches(promote_matches.unwrap()).is_err());
+  }
+
+  #[test]
+  fn test_sync_requires_prune() {
+    let m = app().get_matches_from_safe(vec![
+      "provide", "sync", "-a", "app", "-t", "staging", "-i", "file",
+    ]);
+    assert!(m.is_err());
+    let m = app().get_matches_from(vec![
+      "provide", "sync", "-a", "app", "-t", "staging", "-i", "file", "--prune",
+    ]);
+    let (_, sync_matches) = m.subcommand();
+    let (path, local) = sync_options_from_matches(sync_matches.unwrap()).unwrap();
+    assert_eq!(path, "/app/staging");
+    assert_eq!(local.includes, Some(vec!["file".to_owned()]));
+    assert_eq!(local.mode, None);
+  }
+
+  #[test]
+  fn test_rollback_options() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "rollback",
+      "-a",
+      "app",
+      "-t",
+      "staging",
+      "--to-version",
+      "3",
+      "DB_URL",
+    ]);
+    let (_, rollback_matches) = m.subcommand();
+    let options = rollback_options_from_matches(rollback_matches.unwrap()).unwrap();
+    assert_eq!(
+      options,
+      (
+        "/app/staging".to_owned(),
+        Some("DB_URL".to_owned()),
+        RollbackPoint::VERSION(3)
+      )
+    );
+  }
+
+  #[test]
+  fn test_rollback_version_requires_key() {
+    let m = app().get_matches_from_safe(vec![
+      "provide",
+      "rollback",
+      "-a",
+      "app",
+      "-t",
+      "staging",
+      "--to-version",
+      "3",
+    ]);
+    assert!(m.is_err());
+    let m = app().get_matches_from(vec![
+      "provide",
+      "rollback",
+      "-a",
+      "app",
+      "-t",
+      "staging",
+      "--to-date",
+      "1970-01-01T00:01:40Z",
+    ]);
+    let (_, rollback_matches) = m.subcommand();
+    let (_, key, point) = rollback_options_from_matches(rollback_matches.unwrap()).unwrap();
+    assert_eq!(key, None);
+    assert_eq!(
+      point,
+      RollbackPoint::DATE(api::parse_date_time("1970-01-01T00:01:40Z").unwrap())
+    );
+  }
+
+  #[test]
+  fn test_manifest_config() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "-a",
+      "MyApp",
+      "-t",
+      "staging",
+      "--format",
+      "k8s-secret",
+      "--k8s-label",
+      "team=web",
+      "--k8s-split",
+    ]);
+    let options = options_from_matches(m).unwrap();
+    assert_eq!(options.format_config.format, Format::K8S_SECRET);
+    assert_eq!(
+      options.format_config.manifest,
+      ManifestConfig {
+        name: Some("myapp-staging".to_owned()),
+        namespace: None,
+        labels: vec![("team".to_owned(), "web".to_owned())],
+        split: true,
+      }
+    );
+    let m = app().get_matches_from(vec!["provide", "--k8s-label", "team", "cmd"]);
+    assert!(options_from_matches(m).is_err());
+  }
+
+  #[test]
+  fn test_output_config() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "-e",
+      "FOO=bar",
+      "-o",
+      "app.env",
+      "--output-mode",
+      "0640",
+      "--group",
+      "web",
+    ]);
+    let options = options_from_matches(m).unwrap();
+    assert_eq!(
+      options.format_config.output,
+      Some(OutputConfig {
+        path: "app.env".to_owned(),
+        mode: Some(0o640),
+        owner: None,
+        group: Some("web".to_owned()),
+      })
+    );
+    let m = app().get_matches_from(vec!["provide", "-o", "app.env", "--output-mode", "rw"]);
+    assert!(options_from_matches(m).is_err());
+  }
+
+  #[test]
+  fn test_secret_files() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "--secret-file",
+      "DB_PASSWORD",
+      "--file-vars",
+      "cmd",
+      "--secret-files",
+    ]);
+    let options = options_from_matches(m).unwrap();
+    assert_eq!(
+      options.run_config,
+      Some(RunConfig {
+        cmd: "cmd".to_owned(),
+        // Flags after the command are its own
+        args: vec!["--secret-files".to_owned()],
+        secret_files: Some(SecretFilesConfig {
+          keys: Some(vec!["DB_PASSWORD".to_owned()]),
+          dir: None,
+          file_vars: true,
+        }),
+        ..RunConfig::default()
+      })
+    );
+  }
+
+  #[test]
+  fn test_render_options() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "render",
+      "--template",
+      "in.tmpl",
+      "--out",
+      "out.conf",
+      "-i",
+      "file",
+    ]);
+    let (_, render_matches) = m.subcommand();
+    let render_matches = render_matches.unwrap();
+    assert_eq!(render_matches.value_of("template"), Some("in.tmpl"));
+    let options = options_from_matches(render_matches.clone()).unwrap();
+    assert_eq!(options.includes, Some(vec!["file".to_owned()]));
+    assert_eq!(
+      options.format_config.output.map(|output| output.path),
+      Some("out.conf".to_owned())
+    );
+  }
+
+  #[test]
+  fn test_key_transform_options() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "--key-case",
+      "upper",
+      "--strip-prefix",
+      "app_",
+      "--key-prefix",
+      "MY_",
+      "--rename",
+      "db.url=DATABASE_URL",
+      "--rename",
+      "a=b",
+      "--replace-invalid",
+      "_",
+      "cmd",
+    ]);
+    let options = options_from_matches(m).unwrap();
+    assert_eq!(
+      options.key_transform,
+      KeyTransform {
+        case: KeyCase::UPPER,
+        strip_prefix: Some("app_".to_owned()),
+        prefix: Some("MY_".to_owned()),
+        renames: vec![
+          ("db.url".to_owned(), "DATABASE_URL".to_owned()),
+          ("a".to_owned(), "b".to_owned()),
+        ],
+        replace_invalid: Some('_'),
+      }
+    );
+    assert_eq!(options.run_config.unwrap().cmd, "cmd");
+
+    let m = app().get_matches_from(vec!["provide", "cmd"]);
+    assert_eq!(
+      options_from_matches(m).unwrap().key_transform.case,
+      KeyCase::PRESERVE
+    );
+
+    let m = app().get_matches_from(vec!["provide", "--replace-invalid", "-", "cmd"]);
+    assert_eq!(
+      options_from_matches(m)
+        .unwrap()
+        .key_transform
+        .replace_invalid,
+      Some('-')
+    );
+
+    let m = app().get_matches_from(vec!["provide", "--rename", "nope", "cmd"]);
+    assert!(options_from_matches(m).is_err());
+
+    let m = app().get_matches_from(vec!["provide", "--replace-invalid", "ab", "cmd"]);
+    assert!(options_from_matches(m).is_err());
+  }
+
+  #[test]
+  fn test_key_filter_options() {
+    let m = app().get_matches_from(vec![
+      "provide", "--only", "^DB_", "--only", "^REDIS_", "--except", "_DEBUG$", "cmd",
+    ]);
+    assert_eq!(
+      options_from_matches(m).unwrap().key_filter,
+      KeyFilter {
+        only: Some(vec!["^DB_".to_owned(), "^REDIS_".to_owned()]),
+        except: Some(vec!["_DEBUG$".to_owned()]),
+      }
+    );
+
+    let m = app().get_matches_from(vec!["provide", "cmd"]);
+    assert_eq!(
+      options_from_matches(m).unwrap().key_filter,
+      KeyFilter::default()
+    );
+  }
+
+  #[test]
+  fn test_merge_options() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "--strict-merge",
+      "error",
+      "--lock",
+      "get:^DB_",
+      "--lock",
+      "include:.*",
+      "cmd",
+    ]);
+    assert_eq!(
+      options_from_matches(m).unwrap().merge_config,
+      MergeConfig {
+        strict: StrictMerge::ERROR,
+        locked: vec![
+          ("get".to_owned(), "^DB_".to_owned()),
+          ("include".to_owned(), ".*".to_owned()),
+        ],
+        ..MergeConfig::default()
+      }
+    );
+
+    let m = app().get_matches_from(vec!["provide", "--lock", "^DB_", "cmd"]);
+    assert!(options_from_matches(m).is_err());
+
+    let m = app().get_matches_from(vec![
+      "provide",
+      "--merge-format",
+      "json",
+      "--merge-input",
+      "json",
+      "cmd",
+    ]);
+    let merge_config = options_from_matches(m).unwrap().merge_config;
+    assert_eq!(merge_config.format, MergeFormat::JSON);
+    assert_eq!(merge_config.input, MergeInput::JSON);
+  }
+
+  #[test]
+  fn test_timeout_options() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "--timeout",
+      "5m",
+      "--merge-timeout",
+      "30s",
+      "--grace-period",
+      "2s",
+      "cmd",
+    ]);
+    let options = options_from_matches(m).unwrap();
+    let run_config = options.run_config.unwrap();
+    assert_eq!(run_config.timeout, Some(Duration::from_secs(300)));
+    assert_eq!(run_config.grace_period, Some(Duration::from_secs(2)));
+    assert_eq!(options.merge_config.timeout, Some(Duration::from_secs(30)));
+    assert_eq!(
+      options.merge_config.grace_period,
+      Some(Duration::from_secs(2))
+    );
+
+    let m = app().get_matches_from(vec!["provide", "--timeout", "soon", "cmd"]);
+    assert!(options_from_matches(m).is_err());
+  }
+
+  #[test]
+  fn test_explain_options() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "explain",
+      "-i",
+      "file",
+      "-e",
+      "KEY=VALUE",
+      "--show-values",
+      "hash",
+    ]);
+    let (name, explain_matches) = m.subcommand();
+    assert_eq!(name, "explain");
+    let explain_matches = explain_matches.unwrap();
+    assert_eq!(show_values_from_matches(explain_matches), ShowValues::HASH);
+    let options = options_from_matches(explain_matches.clone()).unwrap();
+    assert_eq!(options.includes, Some(vec!["file".to_owned()]));
+    assert_eq!(options.env_vars, Some(vec!["KEY=VALUE".to_owned()]));
+  }
+
+  #[test]
+  fn test_redact_options() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "--redact",
+      "--redact-key",
+      "PASSWORD",
+      "cmd",
+    ]);
+    assert_eq!(
+      options_from_matches(m).unwrap().run_config.unwrap().redact,
+      Some(RedactConfig {
+        secure: true,
+        keys: vec!["PASSWORD".to_owned()],
+      })
+    );
+
+    let m = app().get_matches_from(vec!["provide", "cmd"]);
+    assert_eq!(
+      options_from_matches(m).unwrap().run_config.unwrap().redact,
+      None
+    );
+  }
+
+  #[test]
+  fn test_watch_options() {
+    let m = app().get_matches_from(vec![
+      "provide", "--watch", "30s", "--signal", "hup", "--output", "vars.env", "cmd",
+    ]);
+    assert_eq!(
+      watch_config_from_matches(&m).unwrap(),
+      Some(WatchConfig {
+        interval: Duration::from_secs(30),
+        signal: Some("hup".to_owned()),
+      })
+    );
+
+    let m = app().get_matches_from(vec!["provide", "cmd"]);
+    assert_eq!(watch_config_from_matches(&m).unwrap(), None);
+
+    let m = app().get_matches_from(vec!["provide", "--watch", "soon", "cmd"]);
+    assert!(watch_config_from_matches(&m).is_err());
+
+    let m = app().get_matches_from(vec![
+      "provide", "--watch", "1m", "--signal", "NOPE", "--output", "vars.env", "cmd",
+    ]);
+    assert!(watch_config_from_matches(&m).is_err());
+  }
+
+  #[test]
+  fn test_restart_options() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "--restart",
+      "on-failure",
+      "--max-restarts",
+      "5",
+      "--restart-delay",
+      "500ms",
+      "cmd",
+    ]);
+    assert_eq!(
+      supervisor_config_from_matches(&m).unwrap(),
+      SupervisorConfig {
+        watch: None,
+        restart: RestartConfig {
+          policy: RestartPolicy::ON_FAILURE,
+          max_restarts: Some(5),
+          delay: Duration::from_millis(500),
+          max_delay: Duration::from_secs(60),
+        },
+      }
+    );
+
+    let m = app().get_matches_from(vec!["provide", "cmd"]);
+    assert_eq!(
+      supervisor_config_from_matches(&m).unwrap(),
+      SupervisorConfig::default()
+    );
+
+    let m = app().get_matches_from(vec![
+      "provide",
+      "--restart",
+      "always",
+      "--max-restarts",
+      "many",
+      "cmd",
+    ]);
+    assert!(supervisor_config_from_matches(&m).is_err());
+  }
+
+  #[test]
+  fn test_process_list_options() {
+    let m = app().get_matches_from(vec![
+      "provide",
+      "-e",
+      "A=1",
+      "--concurrent",
+      "--keep-going",
+      "--redact",
+      "--",
+      "./bin/migrate",
+      "up",
+      "--",
+      "server",
+      "--port",
+      "80",
+      "--",
+      "/usr/bin/server",
+    ]);
+    let process_list = process_list_from_matches(&m).unwrap().unwrap();
+    assert_eq!(process_list.mode, RunMode::CONCURRENT);
+    assert!(process_list.keep_going);
+    let commands: Vec<(&str, Vec<String>, Option<String>)> = process_list
+      .commands
+      .iter()
+      .map(|c| (c.cmd.as_str(), c.args.clone(), c.name.clone()))
+      .collect();
+    assert_eq!(
+      commands,
+      vec![
+        (
+          "./bin/migrate",
+          vec!["up".to_owned()],
+          Some("migrate".to_owned())
+        ),
+        (
+          "server",
+          vec!["--port".to_owned(), "80".to_owned()],
+          Some("server".to_owned())
+        ),
+        ("/usr/bin/server", vec![], Some("server.2".to_owned())),
+      ]
+    );
+    assert!(process_list.commands.iter().all(|c| c.redact.is_some()));
+
+    // Without --sequential or --concurrent, -- is given to the command
+    let m = app().get_matches_from(vec!["provide", "cargo", "run", "--", "--release"]);
+    assert_eq!(process_list_from_matches(&m).unwrap(), None);
+    assert_eq!(
+      options_from_matches(m).unwrap().run_config.unwrap().args,
+      vec!["run", "--", "--release"]
+    );
+  }
 }
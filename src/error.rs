@@ -1,9 +1,13 @@
-use aws_sdk_ssm::error::GetParametersByPathError;
+use aws_sdk_ssm::error::{
+  DeleteParametersError, DescribeParametersError, GetParameterHistoryError,
+  GetParametersByPathError, GetParametersError, PutParameterError,
+};
 use aws_sdk_ssm::types::SdkError;
 use base64;
 use regex;
 use std::env;
 use std::str;
+use std::time::Duration;
 use thiserror::Error as ThisError;
 
 #[derive(ThisError, Debug)]
@@ -16,16 +20,38 @@ pub enum Error {
   BadRegex(#[from] regex::Error),
   #[error("Base64Error: {0}")]
   Base64Error(#[from] base64::DecodeError),
+  #[error("DeleteParametersError: {0}")]
+  DeleteParametersError(#[from] SdkError<DeleteParametersError>),
+  #[error("DescribeParametersError: {0}")]
+  DescribeParametersError(#[from] SdkError<DescribeParametersError>),
+  #[error("DiffError: {0}")]
+  DiffError(String),
   #[error("EnvError: {0}")]
   EnvError(#[from] env::VarError),
   #[error("Error: {0}")]
   Error(String),
+  #[error("GetParameterHistoryError: {0}")]
+  GetParameterHistoryError(#[from] SdkError<GetParameterHistoryError>),
+  #[error("GetParametersError: {0}")]
+  GetParametersError(#[from] SdkError<GetParametersError>),
   #[error("GetParametersByPathError: {0}")]
   GetParametersByPathError(#[from] SdkError<GetParametersByPathError>),
   #[error("InvalidPathError: {0}")]
   InvalidPathError(String),
   #[error("IOError: {0}")]
   IOError(#[from] std::io::Error),
+  #[error("JsonError: {0}")]
+  JsonError(#[from] serde_json::Error),
+  #[error("KilledError: {0} did not exit within {1:?} of SIGTERM and was killed")]
+  KilledError(String, Duration),
+  #[error("MergeError: {0}")]
+  MergeError(String),
+  #[error("PutParameterError: {0}")]
+  PutParameterError(#[from] SdkError<PutParameterError>),
+  #[error("TimeoutError: {0} did not exit within {1:?}")]
+  TimeoutError(String, Duration),
+  #[error("UnsafePathError: {0}")]
+  UnsafePathError(String),
   #[error("Utf8Error: {0}")]
   StringUtf8Error(#[from] std::string::FromUtf8Error),
   #[error("Utf8Error: {0}")]
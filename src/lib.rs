@@ -1,3 +1,5 @@
+#![allow(clippy::result_large_err, clippy::large_enum_variant)]
+
 pub mod api;
 pub mod error;
 pub mod types;
@@ -1,22 +1,104 @@
-use std::{collections::HashMap, iter::FromIterator};
+use std::{
+  collections::{HashMap, HashSet},
+  fmt,
+  iter::FromIterator,
+  mem,
+  time::Duration,
+};
 
-use aws_sdk_ssm::{model::Parameter, Client};
+use serde::Serialize;
+use zeroize::Zeroize;
 
-#[derive(Debug, PartialEq)]
+use aws_sdk_ssm::{
+  model::{Parameter, ParameterType},
+  types::DateTime,
+  Client,
+};
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Mode {
   GET,
   SET,
 }
 
+/*
+    A value that is never printed, only read through expose(), and zeroed when
+    dropped. Only values still wrapped are zeroed: copies made before, such as
+    values read from sources or overridden while merging, and values taken
+    out with into_exposed() are not.
+*/
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct SecretValue(String);
+
+impl SecretValue {
+  pub fn expose(&self) -> &str {
+    &self.0
+  }
+
+  // Takes the value out, which is then no longer zeroed when dropped
+  pub fn into_exposed(mut self) -> String {
+    mem::take(&mut self.0)
+  }
+}
+
+impl From<String> for SecretValue {
+  fn from(val: String) -> Self {
+    SecretValue(val)
+  }
+}
+
+impl From<&str> for SecretValue {
+  fn from(val: &str) -> Self {
+    SecretValue(val.to_owned())
+  }
+}
+
+impl fmt::Debug for SecretValue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "SecretValue(***)")
+  }
+}
+
+impl fmt::Display for SecretValue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "***")
+  }
+}
+
+impl Drop for SecretValue {
+  fn drop(&mut self) {
+    self.0.zeroize();
+  }
+}
+
 #[derive(Debug, PartialEq, Default)]
+pub struct ProcessedParameters {
+  pub vars: HashMap<String, SecretValue>,
+  // Keys read from SecureString parameters, even if a later source overrode them
+  pub secure_keys: HashSet<String>,
+  // --only and --except patterns that matched no key
+  pub unmatched_patterns: Vec<String>,
+  // The source that set each key
+  pub origins: HashMap<String, Source>,
+  // Keys set again with a different value by a later source, in merge order
+  pub overrides: Vec<Override>,
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
 pub struct ProcessParametersOptions {
   pub app: Option<String>,
+  // Whether SecureStrings read with --get are decrypted like those read with --param
+  pub decrypt: bool,
   pub env_vars: Option<Vec<String>>,
   pub env_vars_base64: Option<Vec<String>>,
   pub format_config: FormatConfig,
   pub includes: Option<Vec<String>>,
+  pub key_filter: KeyFilter,
+  pub key_transform: KeyTransform,
+  pub merge_config: MergeConfig,
   pub merges: Option<Vec<String>>,
   pub mode: Option<Mode>,
+  pub params: Option<Vec<String>>,
   pub path: Option<String>,
   pub run_config: Option<RunConfig>,
   pub target: Option<String>,
@@ -27,6 +109,13 @@ pub struct GetAWSParametersOptions {
   pub acc: Vec<Parameter>,
   pub client: Client,
 }
+// A single parameter fetched by its full name, exposed as `key`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParamSpec {
+  pub name: String,
+  pub key: String,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Pair(pub String, pub String);
 
@@ -46,27 +135,358 @@ impl FromIterator<Pair> for HashMap<String, String> {
   }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
 pub enum Format {
   EXPORT,
+  #[default]
   ENV,
   JSON,
+  DOCKER,
+  K8S_SECRET,
+  K8S_CONFIGMAP,
+  SH,
+  FISH,
+  PWSH,
+  GITHUB_ENV,
+  GITLAB_DOTENV,
+  SYSTEMD,
+  SYSTEMD_DROPIN,
 }
 
-impl Default for Format {
-  fn default() -> Self {
-    Format::ENV
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct FormatConfig {
+  pub format: Format,
+  pub raw: bool,
+  pub manifest: ManifestConfig,
+  // Emit ::add-mask:: workflow commands for SecureString values on stderr
+  pub github_mask: bool,
+  // Unit whose drop-in directory receives SYSTEMD_DROPIN output
+  pub systemd_unit: Option<String>,
+  // Defaults to /etc/systemd/system
+  pub systemd_dir: Option<String>,
+  // Write to a file instead of stdout
+  pub output: Option<OutputConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct OutputConfig {
+  pub path: String,
+  // Defaults to 0600
+  pub mode: Option<u32>,
+  pub owner: Option<String>,
+  pub group: Option<String>,
+}
+
+// Metadata of rendered Kubernetes manifests
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct ManifestConfig {
+  // Defaults to "provide"
+  pub name: Option<String>,
+  pub namespace: Option<String>,
+  pub labels: Vec<(String, String)>,
+  // Put SecureString derived keys in a Secret and the rest in a ConfigMap
+  pub split: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum ShowValues {
+  #[default]
+  MASKED,
+  HASH,
+  FULL,
+}
+
+// One key that differs between two sets of variables
+#[derive(Clone, Debug, PartialEq)]
+pub enum Difference {
+  Added(String, String),
+  Removed(String, String),
+  Changed(String, String, String),
+}
+
+// Regex patterns selecting keys, where `except` wins over `only`
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct KeyFilter {
+  pub only: Option<Vec<String>>,
+  pub except: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct PromoteOptions {
+  pub from_path: String,
+  pub to_path: String,
+  pub key_filter: KeyFilter,
+}
+
+// A parameter to be written to `name`, overwriting an existing one if `overwrite`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Promotion {
+  pub key: String,
+  pub name: String,
+  pub value: SecretValue,
+  pub parameter_type: ParameterType,
+  pub key_id: Option<String>,
+  pub overwrite: bool,
+}
+
+// Values to re-put when rolling back, and names of parameters created since the point in time
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct RollbackPlan {
+  pub promotions: Vec<Promotion>,
+  pub created: Vec<String>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum ReportFormat {
+  #[default]
+  TABLE,
+  JSON,
+}
+
+// Parameter metadata as shown by `provide list`, without its value
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ParameterInfo {
+  pub key: String,
+  #[serde(rename = "type")]
+  pub parameter_type: String,
+  pub version: i64,
+  pub last_modified_date: Option<String>,
+  pub last_modified_user: Option<String>,
+  pub tier: Option<String>,
+  pub description: Option<String>,
+}
+
+// One version of a parameter as shown by `provide history`, value already masked
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistoryEntry {
+  pub version: i64,
+  pub last_modified_date: Option<String>,
+  pub last_modified_user: Option<String>,
+  pub labels: Vec<String>,
+  pub value: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RollbackPoint {
+  VERSION(i64),
+  DATE(DateTime),
+}
+
+// Where a variable was read from
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+  GET { name: String, version: i64 },
+  PARAM { name: String, version: i64 },
+  INCLUDE { path: String, line: usize },
+  ENV_VAR,
+  MERGE(String),
+  PROVIDE,
+}
+
+impl Source {
+  // Named after the flag reading from the source
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Source::GET { .. } => "get",
+      Source::PARAM { .. } => "param",
+      Source::INCLUDE { .. } => "include",
+      Source::ENV_VAR => "env-var",
+      Source::MERGE(_) => "merge",
+      Source::PROVIDE => "provide",
+    }
   }
 }
 
+impl fmt::Display for Source {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let kind = self.kind();
+    match self {
+      Source::GET { name, version } | Source::PARAM { name, version } => {
+        write!(f, "{kind} {name} (version {version})")
+      }
+      Source::INCLUDE { path, line } => write!(f, "{kind} {path}:{line}"),
+      Source::MERGE(command) => write!(f, "{kind} {command}"),
+      Source::ENV_VAR | Source::PROVIDE => write!(f, "{kind}"),
+    }
+  }
+}
+
+// Where a key got its value, as shown by `provide explain`, value already masked
+#[derive(Clone, Debug, PartialEq)]
+pub struct Explanation {
+  pub key: String,
+  pub value: String,
+  pub source: Option<Source>,
+  // Sources whose different values were overridden, in merge order
+  pub overridden: Vec<Source>,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Default)]
-pub struct FormatConfig {
-  pub format: Format,
-  pub raw: bool,
+pub enum StrictMerge {
+  #[default]
+  OFF,
+  WARN,
+  ERROR,
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct MergeConfig {
+  pub strict: StrictMerge,
+  // (source kind, key pattern) of keys later sources cannot override
+  pub locked: Vec<(String, String)>,
+  // How merge commands print variables
+  pub format: MergeFormat,
+  // How merge commands are given the variables merged before them
+  pub input: MergeInput,
+  // How long a merge command may run before it is stopped
+  pub timeout: Option<Duration>,
+  // Time given to exit after SIGTERM before being killed, api::DEFAULT_GRACE_PERIOD when None
+  pub grace_period: Option<Duration>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum MergeFormat {
+  // key=value lines
+  RAW,
+  // key=value lines with base64 encoded values
+  #[default]
+  BASE64,
+  // A JSON object of strings, numbers or booleans
+  JSON,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum MergeInput {
+  // Variables are set in the environment of the command
+  #[default]
+  ENV,
+  // Variables are written to stdin as a JSON object, leaving the environment untouched
+  JSON,
+}
+
+// A key set by `source` over the different value set by `overridden`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Override {
+  pub key: String,
+  pub source: Source,
+  pub overridden: Source,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum KeyCase {
+  #[default]
+  PRESERVE,
+  UPPER,
+  LOWER,
+}
+
+// How keys are renamed before being output or given to the command
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct KeyTransform {
+  pub case: KeyCase,
+  // Removed from keys starting with it, before any other change
+  pub strip_prefix: Option<String>,
+  // Added to every key after any other change
+  pub prefix: Option<String>,
+  // Explicit (from, to) names, used as is instead of any other change
+  pub renames: Vec<(String, String)>,
+  // Replaces characters other than letters, digits and _
+  pub replace_invalid: Option<char>,
 }
 
 #[derive(Clone, Debug, PartialEq, Default)]
 pub struct RunConfig {
   pub cmd: String,
   pub args: Vec<String>,
+  pub secret_files: Option<SecretFilesConfig>,
+  pub redact: Option<RedactConfig>,
+  // Prefixes every line of output when running several commands
+  pub name: Option<String>,
+  // How long the command may run before it is stopped
+  pub timeout: Option<Duration>,
+  // Time given to exit after SIGTERM before being killed, api::DEFAULT_GRACE_PERIOD when None
+  pub grace_period: Option<Duration>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum RunMode {
+  #[default]
+  SEQUENTIAL,
+  CONCURRENT,
+}
+
+// Commands run with the same variables by api::run_all
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct ProcessList {
+  pub commands: Vec<RunConfig>,
+  pub mode: RunMode,
+  // Keep running the other commands when one fails
+  pub keep_going: bool,
+}
+
+// Resolving variables again while the command runs
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchConfig {
+  pub interval: Duration,
+  // Sent to the command when variables change, which restarts it when None
+  pub signal: Option<String>,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum RestartPolicy {
+  #[default]
+  NEVER,
+  ON_FAILURE,
+  ALWAYS,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RestartConfig {
+  pub policy: RestartPolicy,
+  // Restarting forever when None
+  pub max_restarts: Option<u32>,
+  // Before the first restart, doubling with every other one
+  pub delay: Duration,
+  pub max_delay: Duration,
+}
+
+impl Default for RestartConfig {
+  fn default() -> Self {
+    RestartConfig {
+      policy: RestartPolicy::NEVER,
+      max_restarts: None,
+      delay: Duration::from_secs(1),
+      max_delay: Duration::from_secs(60),
+    }
+  }
+}
+
+// How a command is kept running by api::supervise
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct SupervisorConfig {
+  pub watch: Option<WatchConfig>,
+  pub restart: RestartConfig,
+}
+
+// Values replaced with *** in the output of the command
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct RedactConfig {
+  // Values read from SecureString parameters
+  pub secure: bool,
+  // Values of keys matching any of these patterns
+  pub keys: Vec<String>,
+}
+
+// Variables given to the command as files rather than in its environment
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct SecretFilesConfig {
+  // Every variable when None
+  pub keys: Option<Vec<String>>,
+  // Defaults to /dev/shm, or the temp directory where there is none
+  pub dir: Option<String>,
+  // Also set <KEY>_FILE to the path of each file
+  pub file_vars: bool,
 }
@@ -0,0 +1,54 @@
+// Runs provide the way an interactive shell does, under a pseudo-terminal created by script
+#![cfg(target_os = "linux")]
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_command_reads_terminal() {
+  let dir = env::temp_dir().join(format!("provide-terminal-test-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let script = dir.join("read.sh");
+  fs::write(&script, "#!/bin/sh\nread line\necho \"read $line $FOO\"\n").unwrap();
+  fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+  let command_line = format!(
+    "{} -e FOO=bar {}",
+    env!("CARGO_BIN_EXE_provide"),
+    script.display()
+  );
+  let mut terminal = match Command::new("script")
+    .args(["-qec", &command_line, "/dev/null"])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+  {
+    Ok(terminal) => terminal,
+    Err(err) => {
+      eprintln!("Skipping, could not run script: {err}");
+      return;
+    }
+  };
+  terminal.stdin.take().unwrap().write_all(b"hello\n").unwrap();
+  // A command stopped by SIGTTIN for reading the terminal from the background never exits
+  let deadline = Instant::now() + Duration::from_secs(10);
+  let status = loop {
+    if let Some(status) = terminal.try_wait().unwrap() {
+      break status;
+    }
+    if Instant::now() >= deadline {
+      terminal.kill().unwrap();
+      panic!("{command_line} did not exit reading the terminal");
+    }
+    thread::sleep(Duration::from_millis(50));
+  };
+  let mut output = String::new();
+  terminal.stdout.take().unwrap().read_to_string(&mut output).unwrap();
+  fs::remove_dir_all(&dir).unwrap();
+  assert!(status.success());
+  assert!(output.contains("read hello bar"), "{output:?}");
+}